@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+/// Users and groups from passwd and group files, e.g. `/etc/passwd` and `/etc/group`
+#[derive(Clone, Debug, Default)]
+pub struct Accounts {
+    users: HashMap<String, u64>,
+    groups: HashMap<String, u64>,
+    user_names: HashMap<u64, String>,
+    group_names: HashMap<u64, String>,
+}
+
+/// Names and ids of `name:password:id:...` lines. Comments and malformed lines are skipped
+fn parse_ids(content: &str) -> Vec<(String, u64)> {
+    content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next().filter(|name| !name.is_empty())?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((name.to_string(), id))
+        })
+        .collect()
+}
+
+/// Maps both ways. If several names share an id, the first one is its name, as in `getpwuid`
+fn index(entries: Vec<(String, u64)>) -> (HashMap<String, u64>, HashMap<u64, String>) {
+    let mut ids = HashMap::new();
+    let mut names = HashMap::new();
+    for (name, id) in entries {
+        names.entry(id).or_insert_with(|| name.clone());
+        ids.entry(name).or_insert(id);
+    }
+    (ids, names)
+}
+
+impl Accounts {
+    /// Parses contents of passwd and group files
+    pub fn parse(passwd: &str, group: &str) -> Self {
+        let (users, user_names) = index(parse_ids(passwd));
+        let (groups, group_names) = index(parse_ids(group));
+        Self {
+            users,
+            groups,
+            user_names,
+            group_names,
+        }
+    }
+
+    pub fn user_id(&self, name: &str) -> Option<u64> {
+        self.users.get(name).copied()
+    }
+
+    pub fn group_id(&self, name: &str) -> Option<u64> {
+        self.groups.get(name).copied()
+    }
+
+    pub fn user_name(&self, id: u64) -> Option<&str> {
+        self.user_names.get(&id).map(String::as_str)
+    }
+
+    pub fn group_name(&self, id: u64) -> Option<&str> {
+        self.group_names.get(&id).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Accounts;
+
+    #[test]
+    fn parse() {
+        let passwd = "# comment\n\
+            root:x:0:0:root:/root:/bin/bash\n\
+            alice:x:1000:1000::/home/alice:/bin/sh\n\
+            toor:x:0:0::/root:/bin/sh\n\
+            broken:x:abc:1\n\
+            :x:5:5\n";
+        let group = "root:x:0:\nwheel:x:10:alice,bob\nshort:x\n";
+        let accounts = Accounts::parse(passwd, group);
+        assert_eq!(accounts.user_id("alice"), Some(1000));
+        assert_eq!(accounts.user_id("toor"), Some(0));
+        assert_eq!(accounts.user_name(0), Some("root"));
+        assert_eq!(accounts.user_id("broken"), None);
+        assert_eq!(accounts.user_name(5), None);
+        assert_eq!(accounts.group_id("wheel"), Some(10));
+        assert_eq!(accounts.group_name(10), Some("wheel"));
+        assert_eq!(accounts.group_id("short"), None);
+        assert_eq!(accounts.group_id("alice"), None);
+    }
+}
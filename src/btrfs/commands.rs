@@ -60,7 +60,7 @@ cmd!(
     }
 );
 
-impl Parser {
+impl Parser<'_> {
     pub(super) fn read_command<T: Read>(&mut self, reader: &mut OffsetedReader<T>) -> Result<bool> {
         let size = try_read(|| reader.read_u32::<LittleEndian>())?;
         let size = match size {
@@ -96,6 +96,8 @@ impl Parser {
                 self.current_subvol = Some(SubvolumeInfo {
                     source: SubvolumeSource::Btrfs {
                         uuid: cmd.tlv_get_auto(tlv.UUID)?,
+                        parent: None,
+                        ctransid: cmd.tlv_get(tlv.Ctransid).ok(),
                     },
                     overwrite: true,
                     files: HashMap::new(),
@@ -111,6 +113,8 @@ impl Parser {
                 self.current_subvol = Some(SubvolumeInfo {
                     source: SubvolumeSource::Btrfs {
                         uuid: cmd.tlv_get_auto(tlv.UUID)?,
+                        parent: cmd.tlv_get(tlv.CloneUUID).ok(),
+                        ctransid: cmd.tlv_get(tlv.Ctransid).ok(),
                     },
                     overwrite: false,
                     files: HashMap::new(),
@@ -134,17 +138,17 @@ impl Parser {
             Command::Rename => {
                 let from = cmd.tlv_get(tlv.Path)?;
                 let to = cmd.tlv_get(tlv.PathTo)?;
-                let subvol = self.subvol()?;
-                let entry = subvol.pop_file(&from)?;
-                subvol.files.insert(to, entry);
+                self.subvol_with_children(&from)?.rename(&from, to)?;
             }
             Command::Link => {
-                self.subvol()?
-                    .copy_file(&cmd.tlv_get(tlv.Path)?, cmd.tlv_get(tlv.PathLink)?)?;
+                // New name is in `Path`, existing file is in `PathLink`
+                let existing = cmd.tlv_get(tlv.PathLink)?;
+                self.subvol_with(&existing)?
+                    .copy_file(&existing, cmd.tlv_get(tlv.Path)?)?;
             }
             Command::Unlink | Command::Rmdir => {
                 let path = cmd.tlv_get(tlv.Path)?;
-                let subvol = self.subvol()?;
+                let subvol = self.subvol_with(&path)?;
                 subvol.del_file(path)?;
             }
             Command::SetXattr | Command::RemoveXattr => {
@@ -154,14 +158,15 @@ impl Parser {
                 }
             }
             Command::Clone => {
-                self.subvol()?
-                    .copy_file(&cmd.tlv_get(tlv.Path)?, cmd.tlv_get(tlv.ClonePath)?)?;
+                let path = cmd.tlv_get(tlv.Path)?;
+                self.subvol_with(&path)?
+                    .copy_file(&path, cmd.tlv_get(tlv.ClonePath)?)?;
             }
             Command::Chmod => {
                 let path = cmd.tlv_get(tlv.Path)?;
                 let mode = cmd.tlv_get_auto(tlv.Mode)?;
 
-                self.subvol()?.modify(
+                self.subvol_with(&path)?.modify(
                     path,
                     debuggable!(|info: &mut FileInfo| {
                         info.permissions = mode;
@@ -173,7 +178,7 @@ impl Parser {
                 let user = cmd.tlv_get_auto(tlv.Uid)?;
                 let group = cmd.tlv_get_auto(tlv.Gid)?;
 
-                self.subvol()?.modify(
+                self.subvol_with(&path)?.modify(
                     path,
                     debuggable!(|info: &mut FileInfo| {
                         info.user_id = user;
@@ -186,7 +191,7 @@ impl Parser {
                 let accessed = cmd.tlv_get_def(tlv.Atime, self.default_dt)?;
                 let created = cmd.tlv_get_def(tlv.Ctime, self.default_dt)?;
                 let modified = cmd.tlv_get_def(tlv.Mtime, self.default_dt)?;
-                self.subvol()?.modify(
+                self.subvol_with(&path)?.modify(
                     path,
                     debuggable!(|info: &mut FileInfo| {
                         info.accessed = accessed;
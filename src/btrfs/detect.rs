@@ -0,0 +1,73 @@
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+
+/// Root directory of every btrfs subvolume has this inode number
+const SUBVOLUME_ROOT_INODE: u64 = 256;
+
+/// Extracts UUID of the subvolume from `btrfs subvolume show` output
+fn parse_uuid(output: &str) -> Option<String> {
+    output
+        .lines()
+        .map(str::trim)
+        // Skips "Parent UUID" and "Received UUID"
+        .find(|line| line.starts_with("UUID:"))
+        .map(|line| line["UUID:".len()..].trim().to_lowercase())
+        .filter(|uuid| uuid.len() == 36)
+}
+
+/// UUID of btrfs subvolume located at `path` or `None` if it is a plain directory
+pub fn subvolume_uuid(path: &Path) -> io::Result<Option<String>> {
+    let meta = path.metadata()?;
+    if !meta.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Not a directory",
+        ));
+    }
+    if meta.ino() != SUBVOLUME_ROOT_INODE {
+        return Ok(None);
+    }
+    // Other filesystems may have directory with the same inode, so btrfs is asked too
+    let output = match Command::new("btrfs")
+        .arg("subvolume")
+        .arg("show")
+        .arg(path)
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return Ok(None),
+    };
+    Ok(parse_uuid(&String::from_utf8_lossy(&output.stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_uuid, subvolume_uuid};
+
+    #[test]
+    fn uuid() {
+        let output = "home
+	Name: 			home
+	UUID: 			1E4F7E2A-5C3b-4a47-9c43-0f1d1e2b3c4d
+	Parent UUID: 		-
+	Received UUID: 		-
+	Creation time: 		2019-08-01 12:00:00 +0300
+";
+        assert_eq!(
+            parse_uuid(output).unwrap(),
+            "1e4f7e2a-5c3b-4a47-9c43-0f1d1e2b3c4d"
+        );
+        assert_eq!(parse_uuid("\tParent UUID: \t-\n"), None);
+    }
+
+    #[test]
+    fn plain_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(subvolume_uuid(dir.path()).unwrap(), None);
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(subvolume_uuid(&file).is_err());
+    }
+}
@@ -2,6 +2,7 @@
 mod utils;
 
 mod commands;
+pub mod detect;
 pub mod parser;
 mod subvolume;
 mod tlv;
@@ -2,7 +2,8 @@
 //      values: https://github.com/torvalds/linux/blob/master/fs/btrfs/send.h
 //      reference: https://github.com/torvalds/linux/blob/master/fs/btrfs/send.c
 
-use crate::model::SubvolumeInfo;
+use crate::mixed::MixedString;
+use crate::model::{FileInfo, SubvolumeInfo};
 use crate::offseted_reader::OffsetedReader;
 use byteorder::{LittleEndian, ReadBytesExt};
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
@@ -24,15 +25,26 @@ impl Default for Settings {
     }
 }
 
-pub struct Parser {
+/// Access to already indexed files of parent snapshots, used by incremental streams
+pub trait Lookup {
+    /// Indexed state of `path` in the subvolume with `uuid`
+    fn file(&mut self, uuid: u128, path: &MixedString) -> Result<Option<FileInfo>>;
+
+    /// Indexed state of everything inside of `path` directory (excluding itself)
+    fn children(&mut self, uuid: u128, path: &MixedString) -> Result<Vec<FileInfo>>;
+}
+
+pub struct Parser<'a> {
     pub current_subvol: Option<SubvolumeInfo>,
     pub result: Vec<SubvolumeInfo>,
     pub command_no: u64,
     pub default_dt: NaiveDateTime,
     pub settings: Settings,
+    pub(super) lookup: Option<&'a mut dyn Lookup>,
 }
 
-impl Parser {
+impl<'a> Parser<'a> {
+    /// Parser without lookup is able to read only full streams
     pub fn new(settings: Settings) -> Self {
         Self {
             current_subvol: None,
@@ -43,6 +55,15 @@ impl Parser {
                 NaiveTime::from_hms(23, 58, 59),
             ),
             settings,
+            lookup: None,
+        }
+    }
+
+    /// Parser that loads files modified by incremental stream using `lookup`
+    pub fn with_lookup(settings: Settings, lookup: &'a mut dyn Lookup) -> Self {
+        Self {
+            lookup: Some(lookup),
+            ..Self::new(settings)
         }
     }
 
@@ -73,6 +94,34 @@ impl Parser {
             .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No subvolume specified"))
     }
 
+    /// Same as `subvol`, but `path` is loaded from parent snapshot if needed
+    pub(super) fn subvol_with(&mut self, path: &MixedString) -> Result<&mut SubvolumeInfo> {
+        let subvol = self
+            .current_subvol
+            .as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No subvolume specified"))?;
+        if let Some(lookup) = &mut self.lookup {
+            subvol.load_file(path, &mut **lookup)?;
+        }
+        Ok(subvol)
+    }
+
+    /// Same as `subvol_with`, but contents of `path` directory are loaded too
+    pub(super) fn subvol_with_children(
+        &mut self,
+        path: &MixedString,
+    ) -> Result<&mut SubvolumeInfo> {
+        let subvol = self
+            .current_subvol
+            .as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No subvolume specified"))?;
+        if let Some(lookup) = &mut self.lookup {
+            subvol.load_file(path, &mut **lookup)?;
+            subvol.load_children(path, &mut **lookup)?;
+        }
+        Ok(subvol)
+    }
+
     fn read_header<T: Read>(reader: &mut T) -> Result<()> {
         const CORRECT_MAGIC: [u8; 13] = [
             0x62, 0x74, 0x72, 0x66, 0x73, 0x2d, // btrfs-
@@ -1,5 +1,6 @@
+use crate::btrfs::parser::Lookup;
 use crate::mixed::MixedString;
-use crate::model::{FileInfo, FileType, SubvolumeInfo};
+use crate::model::{FileInfo, FileType, SubvolumeInfo, SubvolumeSource};
 
 use chrono::NaiveDateTime;
 use std::collections::hash_map::Entry;
@@ -60,7 +61,6 @@ impl SubvolumeInfo {
     }
 
     pub(super) fn get_file(&mut self, path: &MixedString) -> Result<&mut Option<FileInfo>> {
-        self.load_file(path);
         self.files.get_mut(path).ok_or_else(|| {
             Error::new(
                 ErrorKind::InvalidData,
@@ -70,8 +70,13 @@ impl SubvolumeInfo {
     }
 
     pub(super) fn pop_file(&mut self, path: &MixedString) -> Result<Option<FileInfo>> {
-        self.load_file(path);
-        self.files.remove(path).ok_or_else(|| {
+        let entry = if self.overwrite {
+            self.files.remove(path)
+        } else {
+            // Old path should be removed from the database too
+            self.files.get_mut(path).map(Option::take)
+        };
+        entry.ok_or_else(|| {
             Error::new(
                 ErrorKind::InvalidData,
                 format!("Renaming, but old file not found: {}", path),
@@ -79,19 +84,87 @@ impl SubvolumeInfo {
         })
     }
 
+    /// Moves `from` and everything inside of it to `to`
+    pub(super) fn rename(&mut self, from: &MixedString, to: MixedString) -> Result<()> {
+        let mut prefix = from.to_bytes();
+        prefix.push(b'/');
+        let inside: Vec<MixedString> = self
+            .files
+            .iter()
+            .filter(|(path, info)| info.is_some() && path.to_bytes().starts_with(&prefix))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let mut entry = self.pop_file(from)?;
+        if let Some(info) = &mut entry {
+            info.filename = to.clone();
+        }
+        let to_bytes = to.to_bytes();
+        self.files.insert(to, entry);
+
+        for path in inside {
+            let mut new_path = to_bytes.clone();
+            new_path.extend_from_slice(&path.to_bytes()[prefix.len() - 1..]);
+            let new_path = MixedString::from_bytes(&new_path);
+            let mut entry = self.pop_file(&path)?;
+            if let Some(info) = &mut entry {
+                info.filename = new_path.clone();
+            }
+            self.files.insert(new_path, entry);
+        }
+        Ok(())
+    }
+
+    const fn parent(&self) -> Option<u128> {
+        match self.source {
+            SubvolumeSource::Btrfs { parent, .. } => parent,
+            SubvolumeSource::Find { .. } => None,
+        }
+    }
+
     /// Loads file from database to subvolume. Useful for modifying files, but useless if in overwrite mode
-    pub(super) fn load_file(&mut self, path: &MixedString) {
+    pub(super) fn load_file(&mut self, path: &MixedString, lookup: &mut dyn Lookup) -> Result<()> {
         if self.overwrite {
-            return;
+            return Ok(());
         }
         if self.files.contains_key(path) {
-            return;
+            return Ok(());
+        }
+        let Some(parent) = self.parent() else {
+            return Ok(());
+        };
+        if let Some(info) = lookup.file(parent, path)? {
+            self.files.insert(path.clone(), Some(info));
+        }
+        Ok(())
+    }
+
+    /// Loads contents of `path` directory, that were not changed by this stream yet
+    pub(super) fn load_children(
+        &mut self,
+        path: &MixedString,
+        lookup: &mut dyn Lookup,
+    ) -> Result<()> {
+        if self.overwrite {
+            return Ok(());
+        }
+        match self.files.get(path) {
+            Some(Some(info)) if info.filetype == FileType::Directory => {}
+            _ => return Ok(()),
+        }
+        let Some(parent) = self.parent() else {
+            return Ok(());
+        };
+        for info in lookup.children(parent, path)? {
+            // Files that are already known are newer
+            if !self.files.contains_key(&info.filename) {
+                self.files.insert(info.filename.clone(), Some(info));
+            }
         }
-        // TODO: Not implemented
+        Ok(())
     }
 
     pub(super) fn copy_file(&mut self, from: &MixedString, to: MixedString) -> Result<()> {
-        self.load_file(from);
         let mut entry = self.get_file(from)?.clone();
         if let Some(info) = &mut entry {
             info.filename = to.clone();
@@ -104,7 +177,6 @@ impl SubvolumeInfo {
     where
         F: FnOnce(&mut FileInfo) -> T,
     {
-        self.load_file(&path);
         match self.files.entry(path) {
             Entry::Occupied(mut val) => match val.get_mut() {
                 Some(info) => {
@@ -126,19 +198,71 @@ impl SubvolumeInfo {
 
 #[cfg(test)]
 mod tests {
+    use crate::btrfs::parser::Lookup;
     use crate::btrfs::utils::Debuggable;
     use crate::mixed::MixedString;
     use crate::model::{FileInfo, FileType, SubvolumeInfo, SubvolumeSource};
+    use chrono::NaiveDateTime;
     use std::collections::HashMap;
+    use std::io::Result;
 
     fn get_subvol(overwrite: bool) -> SubvolumeInfo {
         SubvolumeInfo {
-            source: SubvolumeSource::Btrfs { uuid: 0 },
+            source: SubvolumeSource::Btrfs {
+                uuid: 0,
+                parent: if overwrite { None } else { Some(1) },
+                ctransid: None,
+            },
             overwrite,
             files: HashMap::new(),
         }
     }
 
+    /// Indexed state of parent snapshot with uuid 1
+    struct Parent(HashMap<MixedString, FileInfo>);
+
+    impl Parent {
+        fn new(paths: &[(&str, FileType)]) -> Self {
+            let files = paths
+                .iter()
+                .map(|(path, filetype)| {
+                    let path: MixedString = (*path).into();
+                    let info = FileInfo {
+                        filename: path.clone(),
+                        permissions: 123,
+                        modified: NaiveDateTime::from_timestamp(0, 0),
+                        accessed: NaiveDateTime::from_timestamp(0, 0),
+                        created: NaiveDateTime::from_timestamp(0, 0),
+                        length: 0,
+                        user_id: 0,
+                        group_id: 0,
+                        filetype: *filetype,
+                    };
+                    (path, info)
+                })
+                .collect();
+            Self(files)
+        }
+    }
+
+    impl Lookup for Parent {
+        fn file(&mut self, uuid: u128, path: &MixedString) -> Result<Option<FileInfo>> {
+            assert_eq!(uuid, 1);
+            Ok(self.0.get(path).cloned())
+        }
+
+        fn children(&mut self, uuid: u128, path: &MixedString) -> Result<Vec<FileInfo>> {
+            assert_eq!(uuid, 1);
+            let prefix = format!("{}/", path.to_string());
+            let children = self
+                .0
+                .values()
+                .filter(|info| info.filename.to_string().starts_with(&prefix))
+                .cloned();
+            Ok(children.collect())
+        }
+    }
+
     fn validate(info: &FileInfo, path: &MixedString) {
         assert_eq!(&info.filename, path);
         assert_eq!(info.permissions, 123);
@@ -283,6 +407,78 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn load() {
+        let mut info = get_subvol(false);
+        let mut parent = Parent::new(&[("a", FileType::Unknown)]);
+        let path: MixedString = "a".into();
+        info.load_file(&path, &mut parent).unwrap();
+        info.modify(
+            path.clone(),
+            debuggable!(|x: &mut FileInfo| x.user_id = 999),
+        )
+        .unwrap();
+
+        let v = info.files.get(&path).unwrap().as_ref().unwrap();
+        validate(v, &path);
+        assert_eq!(v.user_id, 999);
+
+        // Missing files are not loaded
+        let missing: MixedString = "b".into();
+        info.load_file(&missing, &mut parent).unwrap();
+        assert!(info.get_file(&missing).is_err());
+    }
+
+    #[test]
+    fn load_keeps_changed() {
+        let mut info = get_subvol(false);
+        let mut parent = Parent::new(&[("a", FileType::File)]);
+        let path: MixedString = "a".into();
+        info.add_file(path.clone(), FileType::Unknown, 1).unwrap();
+        info.load_file(&path, &mut parent).unwrap();
+        let v = info.files.get(&path).unwrap().as_ref().unwrap();
+        assert_eq!(v.permissions, 1);
+    }
+
+    #[test]
+    fn rename_loaded_directory() {
+        let mut info = get_subvol(false);
+        let mut parent = Parent::new(&[
+            ("a", FileType::Directory),
+            ("a/b", FileType::Unknown),
+            ("a/b/c", FileType::Unknown),
+            ("ab", FileType::Unknown),
+        ]);
+        let from: MixedString = "a".into();
+        info.load_file(&from, &mut parent).unwrap();
+        info.load_children(&from, &mut parent).unwrap();
+        info.rename(&from, "d".into()).unwrap();
+
+        for old in &["a", "a/b", "a/b/c"] {
+            let old: MixedString = (*old).into();
+            assert!(info.files.get(&old).unwrap().is_none());
+        }
+        for new in &["d/b", "d/b/c"] {
+            let new: MixedString = (*new).into();
+            validate(info.files.get(&new).unwrap().as_ref().unwrap(), &new);
+        }
+        assert!(!info.files.contains_key(&"ab".into()));
+    }
+
+    #[test]
+    fn rename_overwrite() {
+        let mut info = get_subvol(true);
+        info.add_file("o257".into(), FileType::Unknown, 123)
+            .unwrap();
+        info.add_file("o257/f".into(), FileType::Unknown, 123)
+            .unwrap();
+        info.rename(&"o257".into(), "dir".into()).unwrap();
+
+        assert_eq!(info.files.len(), 2);
+        let new: MixedString = "dir/f".into();
+        validate(info.files.get(&new).unwrap().as_ref().unwrap(), &new);
+    }
+
     #[test]
     fn del_no_overwrite() {
         let mut info = get_subvol(false);
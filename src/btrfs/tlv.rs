@@ -81,6 +81,7 @@ macro_rules! tlv {
 
 tlv!(TLVValue, struct TLV, enum TLVs, reader (
     UUID: u128 = 1, => read_u128;
+    Ctransid: u64 = 2, => read_u64;
     Size: u64 = 4, => read_u64;
     Mode: u64 = 5, => read_u64;
     Uid: u64 = 6, => read_u64;
@@ -94,6 +95,7 @@ tlv!(TLVValue, struct TLV, enum TLVs, reader (
     Path: MixedString = 15, => read_mixed;
     PathTo: MixedString = 16, => read_mixed;
     PathLink: MixedString = 17, => read_mixed;
+    CloneUUID: u128 = 20, => read_u128;
     ClonePath: MixedString = 22, => read_mixed;
 ));
 
@@ -157,7 +159,7 @@ impl Command {
     }
 }
 
-impl Parser {
+impl Parser<'_> {
     pub(super) fn read_tlvs<T: Read>(&mut self, reader: &mut OffsetedReader<T>) -> Result<TLV> {
         let mut res = TLV::new();
         loop {
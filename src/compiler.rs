@@ -0,0 +1,1576 @@
+use crate::accounts::Accounts;
+use crate::literal::{parse_perm, parse_size, parse_time, PermMatch, Time, Timezone};
+use crate::mixed::MixedString;
+use crate::model::FileType;
+use crate::pattern::{sql_function, Pattern, Scope, Syntax};
+use crate::query::{
+    Argument, Command, CommandKind, ExprKind, Expression, Modifier, Op, Query, Span, Value,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rusqlite::types::Value as SqlValue;
+use std::collections::HashMap;
+use std::fmt;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Table that the condition is evaluated against, aliased as `"f"`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// Indexed files, terms are prefiltered by full-text indices
+    Files,
+    /// Recorded versions of files, which have neither full-text indices nor depth
+    History,
+}
+
+/// SQL condition over columns of `"f"`. Every value from the query is bound as `?N` parameter,
+/// so the query text never gets into SQL itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub sql: String,
+    pub params: Vec<SqlValue>,
+    /// Whether ages or `today` are used, so the same files match differently over time
+    pub uses_now: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub message: String,
+    /// Where the error is in the compiled query. Errors inside of macros are reported
+    /// at their references, the message tells the exact place
+    pub span: Span,
+    /// Whether the message already tells the place inside of a macro
+    in_macro: bool,
+}
+
+impl Error {
+    const fn new(message: String, span: Span) -> Self {
+        Self {
+            message,
+            span,
+            in_macro: false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+/// Start of reversed paths ending with `suffix`, as it is written to "`rev_path`".
+/// Only the trailing valid part of `suffix` is used, and its first grapheme is skipped,
+/// because it may be joined with preceding characters of the path
+fn reversed_prefix(suffix: &MixedString) -> String {
+    let last = match suffix.strings().last() {
+        Some(last) if suffix.to_bytes().ends_with(last.as_bytes()) => last,
+        _ => return String::new(),
+    };
+    let mut graphemes: Vec<&str> = last.graphemes(true).rev().collect();
+    graphemes.pop();
+    graphemes.concat()
+}
+
+/// Whole string is a single FTS5 string, quotes inside of it are doubled
+fn fts_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+fn unknown_modifier(modifier: &Modifier) -> Error {
+    Error::new(
+        format!("Unknown modifier `{}`", modifier.name.text),
+        modifier.span,
+    )
+}
+
+/// Integer columns compared with `name=value`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Column {
+    Mode,
+    Uid,
+    Gid,
+    Type,
+    Length,
+    Atime,
+    Mtime,
+    Ctime,
+    Depth,
+}
+
+impl Column {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mode" => Some(Self::Mode),
+            "uid" => Some(Self::Uid),
+            "gid" => Some(Self::Gid),
+            "type" => Some(Self::Type),
+            "length" | "size" => Some(Self::Length),
+            "atime" => Some(Self::Atime),
+            "mtime" => Some(Self::Mtime),
+            "ctime" => Some(Self::Ctime),
+            "depth" => Some(Self::Depth),
+            _ => None,
+        }
+    }
+
+    const fn sql(self) -> &'static str {
+        match self {
+            // Type bits of `st_mode` are not a part of permissions
+            Self::Mode => r#"("f"."mode" & 4095)"#,
+            Self::Uid => r#""f"."uid""#,
+            Self::Gid => r#""f"."gid""#,
+            Self::Type => r#""f"."type""#,
+            Self::Length => r#""f"."length""#,
+            Self::Atime => r#""f"."atime""#,
+            Self::Mtime => r#""f"."mtime""#,
+            Self::Ctime => r#""f"."ctime""#,
+            Self::Depth => r#""f"."depth""#,
+        }
+    }
+
+    pub const fn is_time(self) -> bool {
+        matches!(self, Self::Atime | Self::Mtime | Self::Ctime)
+    }
+
+    /// Stored value of `text`: octal permissions, type name, size with units or plain number.
+    /// Times are parsed by `literal::parse_time`
+    fn parse(self, text: &str) -> Option<i64> {
+        match self {
+            Self::Mode => i64::from_str_radix(text, 8)
+                .ok()
+                .filter(|&mode| mode <= 0o7777),
+            Self::Type => FileType::from_name(text).map(|t| i64::from(t.to_num())),
+            Self::Length => parse_size(text),
+            _ => text.parse().ok().filter(|&num: &i64| num >= 0),
+        }
+    }
+}
+
+/// Built-in functions called as `name(args...)`. Other names are macros with parameters
+pub const FUNCTIONS: &[&str] = &[
+    "suid",
+    "sgid",
+    "sticky",
+    "re",
+    "path_re",
+    "glob",
+    "path_glob",
+];
+
+/// Type of macro parameter. Arguments are checked before they are substituted
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    /// Anything, e.g. part of a path or a pattern
+    Text,
+    Number,
+    /// Size with optional unit, see `literal::parse_size`
+    Size,
+    /// Date or age, see `literal::parse_time`
+    Time,
+}
+
+impl ParamType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "number" => Some(Self::Number),
+            "size" => Some(Self::Size),
+            "time" => Some(Self::Time),
+            _ => None,
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Number => "number",
+            Self::Size => "size",
+            Self::Time => "time",
+        }
+    }
+
+    fn accepts(self, text: &str, options: &Options) -> bool {
+        match self {
+            Self::Text => true,
+            Self::Number => text.parse::<i64>().is_ok(),
+            Self::Size => parse_size(text).is_some(),
+            Self::Time => parse_time(text, options.timezone, &options.now).is_some(),
+        }
+    }
+}
+
+/// Parameter of a macro, referenced in its query as `$name`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Param {
+    pub name: String,
+    pub kind: ParamType,
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ParamType::Text => write!(f, "{}", self.name),
+            kind => write!(f, "{}: {}", self.name, kind.name()),
+        }
+    }
+}
+
+/// Parses comma-separated parameters like `size: size, dir`. Type is `text` unless it is given
+pub fn parse_params(text: &str) -> Result<Vec<Param>, String> {
+    let mut params: Vec<Param> = Vec::new();
+    if text.trim().is_empty() {
+        return Ok(params);
+    }
+    for param in text.split(',') {
+        let (name, kind) = param.split_once(':').map_or_else(
+            || (param.trim(), "text"),
+            |(name, kind)| (name.trim(), kind.trim()),
+        );
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!(
+                "Invalid parameter name `{name}`. Use letters, digits and `_`"
+            ));
+        }
+        if params.iter().any(|param| param.name == name) {
+            return Err(format!("Parameter `{name}` is declared twice"));
+        }
+        let kind = ParamType::from_name(kind).ok_or_else(|| {
+            format!("Unknown type `{kind}` of parameter `{name}`. Use text, number, size or time")
+        })?;
+        params.push(Param {
+            name: name.to_string(),
+            kind,
+        });
+    }
+    Ok(params)
+}
+
+/// Replaces `$name` in the value by the argument of parameter `name`, `$$` stands for `$`
+fn substitute_value(value: &Value, args: &HashMap<&str, &str>) -> Result<Value, Error> {
+    let mut text = String::with_capacity(value.text.len());
+    let mut rest = value.text.as_str();
+    while let Some(pos) = rest.find('$') {
+        text.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            text.push('$');
+            rest = after;
+            continue;
+        }
+        let len = after
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(after.len());
+        let name = &after[..len];
+        let arg = args.get(name).ok_or_else(|| {
+            let message = if name.is_empty() {
+                "Parameter name is expected after `$`, use `$$` for the dollar sign".to_string()
+            } else {
+                format!("Unknown parameter `${name}`")
+            };
+            Error::new(message, value.span)
+        })?;
+        text.push_str(arg);
+        rest = &after[len..];
+    }
+    text.push_str(rest);
+    Ok(Value {
+        text,
+        quoted: value.quoted,
+        span: value.span,
+    })
+}
+
+fn substitute_modifier(modifier: &Modifier, args: &HashMap<&str, &str>) -> Result<Modifier, Error> {
+    Ok(Modifier {
+        name: substitute_value(&modifier.name, args)?,
+        value: match &modifier.value {
+            Some(value) => Some(substitute_value(value, args)?),
+            None => None,
+        },
+        span: modifier.span,
+    })
+}
+
+fn substitute_command(command: &Command, args: &HashMap<&str, &str>) -> Result<Command, Error> {
+    let value = |value: &Value| substitute_value(value, args);
+    let kind = match &command.kind {
+        CommandKind::Term(term) => CommandKind::Term(value(term)?),
+        CommandKind::Compare { left, op, right } => CommandKind::Compare {
+            left: value(left)?,
+            op: *op,
+            right: value(right)?,
+        },
+        CommandKind::Macro { name, args } => CommandKind::Macro {
+            name: value(name)?,
+            args: args.iter().map(value).collect::<Result<_, _>>()?,
+        },
+        CommandKind::Function { name, args } => {
+            let mut substituted = Vec::with_capacity(args.len());
+            for arg in args {
+                substituted.push(Argument {
+                    name: match &arg.name {
+                        Some(name) => Some(value(name)?),
+                        None => None,
+                    },
+                    value: value(&arg.value)?,
+                    span: arg.span,
+                });
+            }
+            CommandKind::Function {
+                name: value(name)?,
+                args: substituted,
+            }
+        }
+    };
+    Ok(Command {
+        modifiers: command
+            .modifiers
+            .iter()
+            .map(|modifier| substitute_modifier(modifier, args))
+            .collect::<Result<_, _>>()?,
+        kind,
+        span: command.span,
+    })
+}
+
+/// Whether `text` references a parameter as `$name`. Only values of a macro that is being
+/// checked are left with references after substitution
+fn has_param(text: &str) -> bool {
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            continue;
+        }
+        match chars.peek() {
+            Some('$') => {
+                chars.next();
+            }
+            Some(&c) if c.is_alphanumeric() || c == '_' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Copy of `expr` with parameters replaced by arguments in every value
+fn substitute(expr: &Expression, args: &HashMap<&str, &str>) -> Result<Expression, Error> {
+    let list = |items: &[Expression]| -> Result<Vec<Expression>, Error> {
+        items.iter().map(|item| substitute(item, args)).collect()
+    };
+    let kind = match &expr.kind {
+        ExprKind::Or(items) => ExprKind::Or(list(items)?),
+        ExprKind::And(items) => ExprKind::And(list(items)?),
+        ExprKind::Command(command) => ExprKind::Command(substitute_command(command, args)?),
+        ExprKind::Not(inner) => ExprKind::Not(Box::new(substitute(inner, args)?)),
+        ExprKind::Modified { modifiers, expr } => ExprKind::Modified {
+            modifiers: modifiers
+                .iter()
+                .map(|modifier| substitute_modifier(modifier, args))
+                .collect::<Result<_, _>>()?,
+            expr: Box::new(substitute(expr, args)?),
+        },
+    };
+    Ok(Expression {
+        kind,
+        span: expr.span,
+    })
+}
+
+/// Saved query referenced as `{name args...}` or `name(args...)`
+#[derive(Clone, Debug)]
+pub struct Macro {
+    pub query: Query,
+    /// Id of the macro in "compiled", if its matching files are precompiled.
+    /// Macros with parameters are never precompiled
+    pub compiled: Option<i64>,
+    pub params: Vec<Param>,
+}
+
+/// Settings of compilation
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub target: Target,
+    /// Timezone of dates in the query
+    pub timezone: Timezone,
+    /// UTC time that ages and `today` are counted from
+    pub now: NaiveDateTime,
+    /// Names of `user=...` and `group=...`
+    pub accounts: Accounts,
+    /// Macros referenced as `{name}` or called as `name(...)`
+    pub macros: HashMap<String, Macro>,
+}
+
+impl Options {
+    /// Options for local dates relative to the current time
+    pub fn new(target: Target) -> Self {
+        Self {
+            target,
+            timezone: Timezone::Local,
+            now: Utc::now().naive_utc(),
+            accounts: Accounts::default(),
+            macros: HashMap::new(),
+        }
+    }
+}
+
+/// Query with expanded macros and parsed literals. It is evaluated either as SQL condition
+/// by `compile` or against files in memory by `matcher::Matcher`, so both see the same query
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Or(Vec<Self>),
+    And(Vec<Self>),
+    Not(Box<Self>),
+    /// Path contains the bytes
+    Contains(Vec<u8>),
+    /// Path ends with the bytes, empty suffix matches everything
+    EndsWith(Vec<u8>),
+    /// `path=...`
+    Path(Vec<u8>),
+    /// `name=...`: path is the name or ends with `/name`
+    Name(Vec<u8>),
+    /// Integer column compared with the value
+    Compare {
+        column: Column,
+        op: Op,
+        value: i64,
+    },
+    /// Time column compared with interval `[start, end)` of the literal
+    Time {
+        column: Column,
+        op: Op,
+        start: i64,
+        end: i64,
+    },
+    Perm {
+        kind: PermMatch,
+        mode: u32,
+    },
+    /// Single bit of the mode is set
+    ModeBit(u32),
+    /// `re`, `glob` and their path variants
+    Pattern {
+        pattern: Pattern,
+        syntax: Syntax,
+        text: String,
+    },
+    /// Expanded `{name}`, `compiled` is the id of precompiled macro
+    Macro {
+        compiled: Option<i64>,
+        body: Box<Self>,
+    },
+}
+
+/// Resolves names of the query into `Predicate`
+struct Compiler<'a> {
+    options: &'a Options,
+    /// Macros being expanded, the innermost is the last
+    expanding: Vec<&'a str>,
+    uses_now: bool,
+    /// Definition of a macro is checked, its arguments are unknown. Values referencing
+    /// parameters are validated when the macro is used
+    checking: bool,
+    /// Inside of `!` or `not(...)`
+    negated: bool,
+}
+
+impl Compiler<'_> {
+    fn expression(&mut self, expr: &Expression) -> Result<Predicate, Error> {
+        match &expr.kind {
+            ExprKind::Or(items) => Ok(Predicate::Or(self.list(items)?)),
+            ExprKind::And(items) => Ok(Predicate::And(self.list(items)?)),
+            ExprKind::Command(command) => self.command(command),
+            ExprKind::Not(expr) => {
+                let outer = std::mem::replace(&mut self.negated, true);
+                let res = self.expression(expr);
+                self.negated = outer;
+                Ok(Predicate::Not(Box::new(res?)))
+            }
+            ExprKind::Modified { modifiers, .. } => Err(self.modifier_error(&modifiers[0])),
+        }
+    }
+
+    /// Whether modifiers apply before or after negation is not defined, so they are rejected
+    /// inside of it even when they are known
+    fn modifier_error(&self, modifier: &Modifier) -> Error {
+        if self.negated {
+            Error::new(
+                format!(
+                    "Modifier `{}` cannot be used inside of negation",
+                    modifier.name.text
+                ),
+                modifier.span,
+            )
+        } else {
+            unknown_modifier(modifier)
+        }
+    }
+
+    fn list(&mut self, items: &[Expression]) -> Result<Vec<Predicate>, Error> {
+        items.iter().map(|item| self.expression(item)).collect()
+    }
+
+    /// Whether `value` is an argument that is not known while the macro is checked
+    fn is_unbound(&self, value: &Value) -> bool {
+        self.checking && has_param(&value.text)
+    }
+
+    /// Stands for a command with unbound arguments, it matches everything
+    const fn unbound() -> Predicate {
+        Predicate::EndsWith(Vec::new())
+    }
+
+    fn command(&mut self, command: &Command) -> Result<Predicate, Error> {
+        if let Some(modifier) = command.modifiers.first() {
+            return Err(self.modifier_error(modifier));
+        }
+        match &command.kind {
+            CommandKind::Term(value) => Ok(Self::term(value)),
+            CommandKind::Compare { left, op, right } => self.compare(left, *op, right),
+            CommandKind::Macro { name, args } => {
+                let args: Vec<Argument> = args
+                    .iter()
+                    .map(|value| Argument {
+                        name: None,
+                        value: value.clone(),
+                        span: value.span,
+                    })
+                    .collect();
+                self.expand(name, &args, command.span)
+            }
+            CommandKind::Function { name, args } => self.function(name, args, command.span),
+        }
+    }
+
+    /// Arguments of `definition` by names of its parameters. Arguments are given by position
+    /// or by name, and are checked against types of parameters
+    fn arguments<'b>(
+        &self,
+        name: &str,
+        definition: &'b Macro,
+        args: &'b [Argument],
+        span: Span,
+    ) -> Result<HashMap<&'b str, &'b str>, Error> {
+        let params = &definition.params;
+        if params.is_empty() {
+            if let Some(arg) = args.first() {
+                return Err(Error::new(
+                    format!("Macro `{name}` takes no arguments"),
+                    arg.span,
+                ));
+            }
+        }
+        let mut bound: HashMap<&str, &str> = HashMap::new();
+        for (i, arg) in args.iter().enumerate() {
+            let param = match &arg.name {
+                Some(arg_name) => params
+                    .iter()
+                    .find(|param| param.name == arg_name.text)
+                    .ok_or_else(|| {
+                        Error::new(
+                            format!("Macro `{}` has no parameter `{}`", name, arg_name.text),
+                            arg_name.span,
+                        )
+                    })?,
+                None => params.get(i).ok_or_else(|| {
+                    let plural = if params.len() == 1 { "" } else { "s" };
+                    Error::new(
+                        format!("Macro `{}` takes {} argument{}", name, params.len(), plural),
+                        arg.span,
+                    )
+                })?,
+            };
+            if !self.is_unbound(&arg.value) && !param.kind.accepts(&arg.value.text, self.options) {
+                return Err(Error::new(
+                    format!(
+                        "Invalid {} `{}` of parameter `{}`",
+                        param.kind.name(),
+                        arg.value.text,
+                        param.name
+                    ),
+                    arg.value.span,
+                ));
+            }
+            if bound.insert(&param.name, &arg.value.text).is_some() {
+                return Err(Error::new(
+                    format!("Parameter `{}` is given twice", param.name),
+                    arg.span,
+                ));
+            }
+        }
+        if let Some(param) = params
+            .iter()
+            .find(|param| !bound.contains_key(&*param.name))
+        {
+            return Err(Error::new(
+                format!("Missing argument `{}` of macro `{}`", param.name, name),
+                span,
+            ));
+        }
+        Ok(bound)
+    }
+
+    /// `{name}` is replaced by the query of the macro, with arguments substituted for its
+    /// parameters. Precompiled macros are checked too, though SQL looks them up in "compiled"
+    fn expand(&mut self, name: &Value, args: &[Argument], span: Span) -> Result<Predicate, Error> {
+        let (name, definition) = self
+            .options
+            .macros
+            .get_key_value(&name.text)
+            .ok_or_else(|| Error::new(format!("Unknown macro `{}`", name.text), name.span))?;
+        let bound = self.arguments(name, definition, args, span)?;
+        if let Some(start) = self.expanding.iter().position(|&outer| outer == name) {
+            let mut chain = self.expanding[start..].to_vec();
+            chain.push(name);
+            return Err(Error::new(
+                format!("Macro `{}` references itself: {}", name, chain.join(" -> ")),
+                span,
+            ));
+        }
+
+        self.expanding.push(name);
+        let query = &definition.query;
+        let res = match query.modifiers.first() {
+            Some(modifier) => Err(self.modifier_error(modifier)),
+            None if definition.params.is_empty() => self.expression(&query.expr),
+            None => substitute(&query.expr, &bound).and_then(|expr| self.expression(&expr)),
+        };
+        self.expanding.pop();
+        let body = res.map_err(|err| {
+            let message = if err.in_macro {
+                err.message
+            } else {
+                format!("{} at {} in macro `{}`", err.message, err.span, name)
+            };
+            Error {
+                message,
+                span,
+                in_macro: true,
+            }
+        })?;
+        Ok(Predicate::Macro {
+            compiled: definition.compiled,
+            body: Box::new(body),
+        })
+    }
+
+    /// Term matches paths containing it, `*suffix` matches paths ending with suffix
+    fn term(value: &Value) -> Predicate {
+        let bytes = value.to_bytes();
+        match bytes.split_first() {
+            Some((b'*', suffix)) if !suffix.contains(&b'*') => Predicate::EndsWith(suffix.to_vec()),
+            _ => Predicate::Contains(bytes),
+        }
+    }
+
+    /// `user=name` or `group=name`. Names are resolved through `Options::accounts`,
+    /// numbers are used as is
+    #[allow(clippy::cast_possible_wrap)]
+    fn owner(&self, name: &str, value: &Value) -> Result<Predicate, Error> {
+        let (column, id) = if name == "user" {
+            (Column::Uid, self.options.accounts.user_id(&value.text))
+        } else {
+            (Column::Gid, self.options.accounts.group_id(&value.text))
+        };
+        let id = id
+            .or_else(|| value.text.parse().ok())
+            .ok_or_else(|| Error::new(format!("Unknown {} `{}`", name, value.text), value.span))?;
+        Ok(Predicate::Compare {
+            column,
+            op: Op::Eq,
+            value: id as i64,
+        })
+    }
+
+    /// `perm=mode` with octal or symbolic mode, see `literal::parse_perm`
+    fn perm(value: &Value) -> Result<Predicate, Error> {
+        let (kind, mode) = parse_perm(&value.text).ok_or_else(|| {
+            Error::new(format!("Invalid permissions: {}", value.text), value.span)
+        })?;
+        Ok(Predicate::Perm { kind, mode })
+    }
+
+    fn function(
+        &mut self,
+        name: &Value,
+        args: &[Argument],
+        span: Span,
+    ) -> Result<Predicate, Error> {
+        match name.text.as_str() {
+            "suid" => Self::mode_bit(name, args, 0o4000),
+            "sgid" => Self::mode_bit(name, args, 0o2000),
+            "sticky" => Self::mode_bit(name, args, 0o1000),
+            "re" => self.pattern(name, args, Syntax::Regex, Scope::Name),
+            "path_re" => self.pattern(name, args, Syntax::Regex, Scope::Path),
+            "glob" => self.pattern(name, args, Syntax::Glob, Scope::Name),
+            "path_glob" => self.pattern(name, args, Syntax::Glob, Scope::Path),
+            _ if self.options.macros.contains_key(&name.text) => self.expand(name, args, span),
+            _ => Err(Error::new(
+                format!("Unknown function `{}`", name.text),
+                name.span,
+            )),
+        }
+    }
+
+    /// `suid()`, `sgid()` and `sticky()` check single bit of the mode
+    fn mode_bit(name: &Value, args: &[Argument], bit: u32) -> Result<Predicate, Error> {
+        if let Some(arg) = args.first() {
+            return Err(Error::new(
+                format!("`{}` takes no arguments", name.text),
+                arg.span,
+            ));
+        }
+        Ok(Predicate::ModeBit(bit))
+    }
+
+    /// `re(regex)` and `glob(glob)` match names of files, `path_re` and `path_glob` match
+    /// whole paths
+    fn pattern(
+        &self,
+        name: &Value,
+        args: &[Argument],
+        syntax: Syntax,
+        scope: Scope,
+    ) -> Result<Predicate, Error> {
+        let [Argument {
+            name: None, value, ..
+        }] = args
+        else {
+            let span = args
+                .iter()
+                .find(|arg| arg.name.is_some())
+                .map_or(name.span, |arg| arg.span);
+            return Err(Error::new(
+                format!("`{}` takes a single pattern", name.text),
+                span,
+            ));
+        };
+        if self.is_unbound(value) {
+            return Ok(Self::unbound());
+        }
+        let pattern = Pattern::new(syntax, scope, &value.text).map_err(|err| {
+            Error::new(
+                format!("Invalid pattern `{}`: {}", value.text, err),
+                value.span,
+            )
+        })?;
+        Ok(Predicate::Pattern {
+            pattern,
+            syntax,
+            text: value.text.clone(),
+        })
+    }
+
+    /// `path` and `name` are compared with the whole path or its last component,
+    /// other names are integer columns
+    fn compare(&mut self, left: &Value, op: Op, right: &Value) -> Result<Predicate, Error> {
+        let name = left.text.as_str();
+        let column = Column::from_name(name);
+        let ordered = !matches!(column, None | Some(Column::Mode | Column::Type));
+        if op != Op::Eq && !ordered {
+            return Err(Error::new(
+                format!("Only `=` can be used with `{name}`"),
+                left.span,
+            ));
+        }
+        let known = column.is_some() || matches!(name, "user" | "group" | "perm");
+        if known && self.is_unbound(right) {
+            return Ok(Self::unbound());
+        }
+        match name {
+            "user" | "group" => return self.owner(name, right),
+            "perm" => return Self::perm(right),
+            "path" => return Ok(Predicate::Path(right.to_bytes())),
+            "name" => return Ok(Predicate::Name(right.to_bytes())),
+            _ => {}
+        }
+
+        let column =
+            column.ok_or_else(|| Error::new(format!("Unknown column `{name}`"), left.span))?;
+        if column == Column::Depth && self.options.target == Target::History {
+            return Err(Error::new(
+                "Depth is not recorded in history".to_string(),
+                left.span,
+            ));
+        }
+        let invalid = || {
+            Error::new(
+                format!("Invalid value of `{}`: {}", name, right.text),
+                right.span,
+            )
+        };
+        if !column.is_time() {
+            let value = column.parse(&right.text).ok_or_else(invalid)?;
+            return Ok(Predicate::Compare { column, op, value });
+        }
+
+        let time = parse_time(&right.text, self.options.timezone, &self.options.now)
+            .ok_or_else(invalid)?;
+        let tomorrow = self.options.now + Duration::days(1);
+        if parse_time(&right.text, self.options.timezone, &tomorrow) != Some(time) {
+            self.uses_now = true;
+        }
+        // Smaller age is a later time
+        let (start, end, op) = match time {
+            Time::Absolute { start, end } => (start, end, op),
+            Time::Age { start, end } => (start, end, op.swapped()),
+        };
+        Ok(Predicate::Time {
+            column,
+            op,
+            start,
+            end,
+        })
+    }
+}
+
+/// Writes `Predicate` as SQL condition
+struct SqlWriter {
+    target: Target,
+    params: Vec<SqlValue>,
+}
+
+impl SqlWriter {
+    /// Placeholder of the new parameter
+    fn bind<T: Into<SqlValue>>(&mut self, value: T) -> String {
+        self.params.push(value.into());
+        format!("?{}", self.params.len())
+    }
+
+    fn predicate(&mut self, predicate: &Predicate) -> String {
+        match predicate {
+            Predicate::Or(items) => format!("({})", self.join(items, " OR ")),
+            Predicate::And(items) => self.join(items, " AND "),
+            Predicate::Not(inner) => self.negation(inner),
+            Predicate::Contains(needle) => self.contains(needle, false),
+            Predicate::EndsWith(suffix) => self.ends_with(suffix, false),
+            Predicate::Path(path) => format!(r#""f"."path" = {}"#, self.bind(path.clone())),
+            Predicate::Name(name) => self.name(name, false),
+            Predicate::Compare { column, op, value } => {
+                format!("{} {} {}", column.sql(), op.as_str(), self.bind(*value))
+            }
+            Predicate::Time {
+                column,
+                op,
+                start,
+                end,
+            } => self.time(*column, *op, *start, *end),
+            Predicate::Perm { kind, mode } => {
+                let mode = self.bind(i64::from(*mode));
+                match kind {
+                    PermMatch::Exact => format!(r#"("f"."mode" & 4095) = {mode}"#),
+                    PermMatch::All => format!(r#"("f"."mode" & {mode}) = {mode}"#),
+                    PermMatch::Any => format!(r#"("f"."mode" & {mode}) != 0"#),
+                }
+            }
+            Predicate::ModeBit(bit) => format!(r#"("f"."mode" & {bit}) != 0"#),
+            Predicate::Pattern {
+                pattern,
+                syntax,
+                text,
+            } => self.pattern(pattern, *syntax, text, false),
+            // History is not precompiled. SQLite plans the subquery as a join over the
+            // primary key of "compiled", see `database::tests::precompiled`
+            Predicate::Macro { compiled, body } => match (compiled, self.target) {
+                (Some(id), Target::Files) => format!(
+                    r#""f"."id" IN (SELECT "file" FROM "compiled" WHERE "macro" = {})"#,
+                    self.bind(*id)
+                ),
+                _ => self.predicate(body),
+            },
+        }
+    }
+
+    fn join(&mut self, items: &[Predicate], separator: &str) -> String {
+        let res: Vec<String> = items.iter().map(|item| self.predicate(item)).collect();
+        res.join(separator)
+    }
+
+    /// Negation is pushed down to the leaves, so lookups in full-text indices become anti-joins:
+    /// files missing from the index match without the exact check
+    fn negation(&mut self, predicate: &Predicate) -> String {
+        let mut join = |items: &[Predicate], separator| {
+            let res: Vec<String> = items.iter().map(|item| self.negation(item)).collect();
+            res.join(separator)
+        };
+        match predicate {
+            Predicate::Or(items) => join(items, " AND "),
+            Predicate::And(items) => format!("({})", join(items, " OR ")),
+            Predicate::Not(inner) => self.predicate(inner),
+            Predicate::Contains(needle) => self.contains(needle, true),
+            Predicate::EndsWith(suffix) => self.ends_with(suffix, true),
+            Predicate::Name(name) => self.name(name, true),
+            Predicate::Pattern {
+                pattern,
+                syntax,
+                text,
+            } => self.pattern(pattern, *syntax, text, true),
+            Predicate::Macro {
+                compiled: None,
+                body,
+            } => self.negation(body),
+            _ => format!("NOT ({})", self.predicate(predicate)),
+        }
+    }
+
+    /// Lookup of `pattern` in full-text `table` followed by the exact `check`. Negated lookup
+    /// is an anti-join by rowid of the file
+    fn indexed(table: &str, pattern: &str, check: &str, negated: bool) -> String {
+        if negated {
+            format!(
+                r#"(NOT EXISTS (SELECT 1 FROM "{table}" WHERE "{table}" MATCH {pattern} AND "rowid" = "f"."fts_id") OR NOT ({check}))"#
+            )
+        } else {
+            format!(
+                r#"("f"."fts_id" IN (SELECT "rowid" FROM "{table}" WHERE "{table}" MATCH {pattern}) AND {check})"#
+            )
+        }
+    }
+
+    /// Path is the name or ends with `/name`
+    fn name(&mut self, name: &[u8], negated: bool) -> String {
+        let mut suffix = b"/".to_vec();
+        suffix.extend_from_slice(name);
+        let top = format!(r#""f"."path" = {}"#, self.bind(name.to_vec()));
+        let nested = self.ends_with(&suffix, negated);
+        if negated {
+            format!("NOT ({top}) AND {nested}")
+        } else {
+            format!("({top} OR {nested})")
+        }
+    }
+
+    /// The pattern is checked by SQL function of `pattern`
+    fn pattern(&mut self, pattern: &Pattern, syntax: Syntax, text: &str, negated: bool) -> String {
+        let check = format!(
+            r#"{}({}, "f"."path")"#,
+            sql_function(syntax, pattern.scope()),
+            self.bind(text.to_string())
+        );
+        self.prefiltered(pattern.required(), &check, negated)
+    }
+
+    /// Needles with at least three valid characters are looked up in the trigram index,
+    /// exact check is done on the original bytes
+    fn contains(&mut self, needle: &[u8], negated: bool) -> String {
+        let check = format!(r#"instr("f"."path", {}) > 0"#, self.bind(needle.to_vec()));
+        self.prefiltered(needle, &check, negated)
+    }
+
+    /// Adds lookup of `required` part of matched paths in the trigram index to `check`
+    fn prefiltered(&mut self, required: &[u8], check: &str, negated: bool) -> String {
+        // Non-UTF-8 parts are escaped in the index, so only valid parts are matched as text
+        let mixed = MixedString::from_bytes(required);
+        let longest = mixed
+            .strings()
+            .max_by_key(|s| s.chars().count())
+            .unwrap_or_default();
+        if self.target == Target::History || longest.chars().count() < 3 {
+            return Self::checked(check, negated);
+        }
+        let pattern = self.bind(fts_string(longest));
+        Self::indexed("files_trigram", &pattern, check, negated)
+    }
+
+    fn checked(check: &str, negated: bool) -> String {
+        if negated {
+            format!("NOT ({check})")
+        } else {
+            check.to_string()
+        }
+    }
+
+    /// Reversed paths are looked up by prefix in the full-text index. The index is
+    /// case-insensitive, exact check is done on the original bytes
+    #[allow(clippy::cast_possible_wrap)]
+    fn ends_with(&mut self, suffix: &[u8], negated: bool) -> String {
+        if suffix.is_empty() {
+            return Self::checked("1", negated);
+        }
+        let check = format!(
+            r#"substr("f"."path", -{}) = {}"#,
+            self.bind(suffix.len() as i64),
+            self.bind(suffix.to_vec())
+        );
+        let prefix = reversed_prefix(&MixedString::from_bytes(suffix));
+        if self.target == Target::History || prefix.is_empty() {
+            return Self::checked(&check, negated);
+        }
+        let pattern = self.bind(format!("rev_path : {} *", fts_string(&prefix)));
+        Self::indexed("files_fts", &pattern, &check, negated)
+    }
+
+    fn time(&mut self, column: Column, op: Op, start: i64, end: i64) -> String {
+        let column = column.sql();
+        match op {
+            Op::Eq => format!(
+                "({column} >= {} AND {column} < {})",
+                self.bind(start),
+                self.bind(end),
+                column = column
+            ),
+            Op::Lt => format!("{} < {}", column, self.bind(start)),
+            Op::Le => format!("{} < {}", column, self.bind(end)),
+            Op::Gt => format!("{} >= {}", column, self.bind(end)),
+            Op::Ge => format!("{} >= {}", column, self.bind(start)),
+        }
+    }
+}
+
+/// Expands macros of `query` and parses its literals. `uses_now` is set if ages or `today`
+/// are used
+pub fn resolve(query: &Query, options: &Options) -> Result<(Predicate, bool), Error> {
+    if let Some(modifier) = query.modifiers.first() {
+        return Err(unknown_modifier(modifier));
+    }
+    let mut compiler = Compiler {
+        options,
+        expanding: Vec::new(),
+        uses_now: false,
+        checking: false,
+        negated: false,
+    };
+    let predicate = compiler.expression(&query.expr)?;
+    Ok((predicate, compiler.uses_now))
+}
+
+/// Compiles the query of macro `name`, so mistakes in it are found when it is saved rather
+/// than when it is used. Values referencing parameters are checked when the macro is used
+pub fn compile_macro(name: &str, options: &Options) -> Result<Condition, Error> {
+    let query = &options.macros[name].query;
+    let params = &options.macros[name].params;
+    if params.is_empty() {
+        return compile(query, options);
+    }
+    if let Some(modifier) = query.modifiers.first() {
+        return Err(unknown_modifier(modifier));
+    }
+    // Parameters are substituted by themselves, so unknown ones and stray `$` are found
+    let references: Vec<String> = params
+        .iter()
+        .map(|param| format!("${}", param.name))
+        .collect();
+    let args: HashMap<&str, &str> = params
+        .iter()
+        .zip(&references)
+        .map(|(param, reference)| (param.name.as_str(), reference.as_str()))
+        .collect();
+    let mut compiler = Compiler {
+        options,
+        expanding: Vec::new(),
+        uses_now: false,
+        checking: true,
+        negated: false,
+    };
+    let predicate = compiler.expression(&substitute(&query.expr, &args)?)?;
+    Ok(write_sql(&predicate, compiler.uses_now, options))
+}
+
+/// Compiles `query` into condition on files of `options.target`
+pub fn compile(query: &Query, options: &Options) -> Result<Condition, Error> {
+    let (predicate, uses_now) = resolve(query, options)?;
+    Ok(write_sql(&predicate, uses_now, options))
+}
+
+fn write_sql(predicate: &Predicate, uses_now: bool, options: &Options) -> Condition {
+    let mut writer = SqlWriter {
+        target: options.target,
+        params: Vec::new(),
+    };
+    let sql = writer.predicate(predicate);
+    Condition {
+        sql,
+        params: writer.params,
+        uses_now,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compile, compile_macro, parse_params, reversed_prefix, Condition, Error, Macro, Options,
+        Param, ParamType, Target,
+    };
+    use crate::accounts::Accounts;
+    use crate::literal::Timezone;
+    use crate::mixed::MixedString;
+    use crate::query::{parse, Span};
+    use chrono::NaiveDate;
+    use rusqlite::types::Value;
+
+    const DAY: i64 = 24 * 60 * 60 * 1_000_000_000;
+
+    /// UTC dates, now is 2024-01-10 00:00:00
+    fn options(target: Target) -> Options {
+        let macros = [
+            ("rust", "", "*.rs && type=file"),
+            ("code", "", "{rust} || *.c"),
+            ("deep", "", "depth>3"),
+            ("ring1", "", "a || {ring2}"),
+            ("ring2", "", "{ring1}"),
+            ("broken", "", "color=1"),
+            ("uses_broken", "", "a && {broken}"),
+            (
+                "large",
+                "size: size, dir",
+                r#"size>$size && path_glob("$dir/**")"#,
+            ),
+            ("price", "amount: number", "$$$amount"),
+            (
+                "nested",
+                "n: number, dir",
+                "{large $n $dir} || large(size=$n, dir=docs)",
+            ),
+            ("typo", "a", "$b"),
+            ("mine", "u", "user=$u"),
+            ("kind", "t", r#"type=$t && re("^$t")"#),
+            ("mine_large", "u", "{mine $u} && {large $u .}"),
+            ("bad_column", "a", "color=$a"),
+            ("bad_order", "t", "type>$t"),
+        ];
+        Options {
+            target,
+            timezone: Timezone::parse("UTC").unwrap(),
+            now: NaiveDate::from_ymd(2024, 1, 10).and_hms(0, 0, 0),
+            accounts: Accounts::parse("alice:x:1000:100::/:/bin/sh\n", "wheel:x:10:alice\n"),
+            macros: macros
+                .iter()
+                .map(|&(name, params, query)| {
+                    let definition = Macro {
+                        query: parse(query).unwrap(),
+                        compiled: if name == "deep" { Some(7) } else { None },
+                        params: parse_params(params).unwrap(),
+                    };
+                    (name.to_string(), definition)
+                })
+                .collect(),
+        }
+    }
+
+    fn compiled(query: &str) -> Condition {
+        compile(&parse(query).unwrap(), &options(Target::Files)).unwrap()
+    }
+
+    fn error(query: &str, target: Target) -> Error {
+        compile(&parse(query).unwrap(), &options(target)).unwrap_err()
+    }
+
+    /// Nanoseconds of UTC midnight of the day in January 2024
+    fn day(day: u32) -> i64 {
+        NaiveDate::from_ymd(2024, 1, day)
+            .and_hms(0, 0, 0)
+            .timestamp()
+            * 1_000_000_000
+    }
+
+    #[test]
+    fn precedence() {
+        let condition = compiled("ab || cd && (ef || gh)");
+        assert_eq!(
+            condition.sql,
+            r#"(instr("f"."path", ?1) > 0 OR instr("f"."path", ?2) > 0 AND (instr("f"."path", ?3) > 0 OR instr("f"."path", ?4) > 0))"#
+        );
+        let params: Vec<Value> = ["ab", "cd", "ef", "gh"]
+            .iter()
+            .map(|s| Value::Blob(s.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(condition.params, params);
+    }
+
+    #[test]
+    fn terms() {
+        let condition = compiled("foo && *.rs");
+        assert_eq!(
+            condition.sql,
+            r#"("f"."fts_id" IN (SELECT "rowid" FROM "files_trigram" WHERE "files_trigram" MATCH ?2) AND instr("f"."path", ?1) > 0) AND ("f"."fts_id" IN (SELECT "rowid" FROM "files_fts" WHERE "files_fts" MATCH ?5) AND substr("f"."path", -?3) = ?4)"#
+        );
+        assert_eq!(condition.params[1], Value::Text("\"foo\"".to_string()));
+        assert_eq!(condition.params[2], Value::Integer(3));
+        assert_eq!(
+            condition.params[4],
+            Value::Text("rev_path : \"sr\" *".to_string())
+        );
+
+        let history = compile(&parse("foo && *.rs").unwrap(), &options(Target::History)).unwrap();
+        assert!(!history.sql.contains("fts"));
+        assert_eq!(compiled("*").sql, "1");
+    }
+
+    #[test]
+    fn columns() {
+        let condition = compiled("type=dir && mode=755 && uid=1000 && depth=2 && mtime=-1");
+        assert_eq!(
+            condition.sql,
+            r#""f"."type" = ?1 AND ("f"."mode" & 4095) = ?2 AND "f"."uid" = ?3 AND "f"."depth" = ?4 AND ("f"."mtime" >= ?5 AND "f"."mtime" < ?6)"#
+        );
+        let params: Vec<Value> = [1, 0o755, 1000, 2, -1_000_000_000, 0]
+            .iter()
+            .map(|&i| Value::Integer(i))
+            .collect();
+        assert_eq!(condition.params, params);
+
+        let condition = compiled("name=\"a b\"");
+        assert_eq!(condition.params[0], Value::Blob(b"a b".to_vec()));
+        assert_eq!(condition.params[2], Value::Blob(b"/a b".to_vec()));
+    }
+
+    #[test]
+    fn comparisons() {
+        let condition = compiled("size>100M && depth<=2 && length>=1KiB");
+        assert_eq!(
+            condition.sql,
+            r#""f"."length" > ?1 AND "f"."depth" <= ?2 AND "f"."length" >= ?3"#
+        );
+        let params: Vec<Value> = [100_000_000, 2, 1024]
+            .iter()
+            .map(|&i| Value::Integer(i))
+            .collect();
+        assert_eq!(condition.params, params);
+
+        // Ages are compared in reverse: less than 7 days old is after 7 days ago
+        let condition = compiled("mtime<7d");
+        assert_eq!(condition.sql, r#""f"."mtime" >= ?1"#);
+        assert_eq!(condition.params, vec![Value::Integer(day(3))]);
+        let condition = compiled("mtime>=7d");
+        assert_eq!(condition.sql, r#""f"."mtime" < ?1"#);
+        assert_eq!(condition.params, vec![Value::Integer(day(3))]);
+        let condition = compiled("mtime>7d");
+        assert_eq!(condition.sql, r#""f"."mtime" < ?1"#);
+        assert_eq!(condition.params, vec![Value::Integer(day(2))]);
+
+        // Dates are whole days
+        let condition = compiled("atime>2024-01-01");
+        assert_eq!(condition.sql, r#""f"."atime" >= ?1"#);
+        assert_eq!(condition.params, vec![Value::Integer(day(2))]);
+        let condition = compiled("atime<=2024-01-01");
+        assert_eq!(condition.sql, r#""f"."atime" < ?1"#);
+        assert_eq!(condition.params, vec![Value::Integer(day(2))]);
+        let condition = compiled("atime<2024-01-01");
+        assert_eq!(condition.params, vec![Value::Integer(day(1))]);
+        let condition = compiled("ctime=today");
+        assert_eq!(condition.sql, r#"("f"."ctime" >= ?1 AND "f"."ctime" < ?2)"#);
+        assert_eq!(
+            condition.params,
+            vec![Value::Integer(day(10)), Value::Integer(day(10) + DAY)]
+        );
+
+        let mut local = options(Target::Files);
+        local.timezone = Timezone::parse("+02:00").unwrap();
+        let condition = compile(&parse("ctime>=2024-01-02").unwrap(), &local).unwrap();
+        assert_eq!(condition.params, vec![Value::Integer(day(2) - DAY / 12)]);
+    }
+
+    #[test]
+    fn owners_and_perms() {
+        let condition = compiled("user=alice && group=wheel || user=0 && group=5");
+        assert_eq!(
+            condition.sql,
+            r#"("f"."uid" = ?1 AND "f"."gid" = ?2 OR "f"."uid" = ?3 AND "f"."gid" = ?4)"#
+        );
+        let params: Vec<Value> = [1000, 10, 0, 5]
+            .iter()
+            .map(|&i| Value::Integer(i))
+            .collect();
+        assert_eq!(condition.params, params);
+
+        let condition = compiled("perm=u+x || perm=-o+w || perm=/111");
+        assert_eq!(
+            condition.sql,
+            r#"(("f"."mode" & 4095) = ?1 OR ("f"."mode" & ?2) = ?2 OR ("f"."mode" & ?3) != 0)"#
+        );
+        let params: Vec<Value> = [0o100, 0o2, 0o111]
+            .iter()
+            .map(|&i| Value::Integer(i))
+            .collect();
+        assert_eq!(condition.params, params);
+
+        assert_eq!(
+            compiled("suid() || sgid() || sticky()").sql,
+            r#"(("f"."mode" & 2048) != 0 OR ("f"."mode" & 1024) != 0 OR ("f"."mode" & 512) != 0)"#
+        );
+        // Without parentheses it is a term
+        assert!(compiled("suid").sql.contains("instr"));
+
+        let err = error("user=bob", Target::Files);
+        assert_eq!(err.to_string(), "Unknown user `bob` at 5..8");
+        assert_eq!(
+            error("group=alice", Target::Files).message,
+            "Unknown group `alice`"
+        );
+        assert_eq!(
+            error("perm=u+q", Target::Files).message,
+            "Invalid permissions: u+q"
+        );
+        assert_eq!(
+            error("user>alice", Target::Files).message,
+            "Only `=` can be used with `user`"
+        );
+        assert_eq!(
+            error("suid(1)", Target::Files).message,
+            "`suid` takes no arguments"
+        );
+    }
+
+    #[test]
+    fn injection() {
+        let condition = compiled(r#""'; DROP TABLE \"files\"; --" || path="x' OR 1=1""#);
+        assert!(!condition.sql.contains("DROP"));
+        assert!(!condition.sql.contains('\''));
+        assert_eq!(
+            condition.params[0],
+            Value::Blob(b"'; DROP TABLE \"files\"; --".to_vec())
+        );
+        // Quotes of FTS strings are doubled
+        assert_eq!(
+            condition.params[1],
+            Value::Text("\"'; DROP TABLE \"\"files\"\"; --\"".to_string())
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = error("a && color=red", Target::Files);
+        assert_eq!(err.message, "Unknown column `color`");
+        assert_eq!(err.span, Span { start: 5, end: 10 });
+        let err = error("uid=root", Target::Files);
+        assert_eq!(err.to_string(), "Invalid value of `uid`: root at 4..8");
+        assert!(error("length=-1", Target::Files)
+            .message
+            .contains("Invalid"));
+        assert!(error("mode=9", Target::Files).message.contains("Invalid"));
+        assert!(error("mode=100644", Target::Files)
+            .message
+            .contains("Invalid"));
+        assert!(error("mtime=99999999999", Target::Files)
+            .message
+            .contains("Invalid"));
+        assert!(error("size>big", Target::Files).message.contains("Invalid"));
+        assert!(error("mtime>soon", Target::Files)
+            .message
+            .contains("Invalid"));
+        assert_eq!(
+            error("type>dir", Target::Files).message,
+            "Only `=` can be used with `type`"
+        );
+        assert_eq!(
+            error("path<=x", Target::Files).message,
+            "Only `=` can be used with `path`"
+        );
+        assert_eq!(
+            error("color<1", Target::Files).message,
+            "Only `=` can be used with `color`"
+        );
+        assert_eq!(error("{big}", Target::Files).message, "Unknown macro `big`");
+        assert_eq!(
+            error("size(1)", Target::Files).message,
+            "Unknown function `size`"
+        );
+        assert_eq!(error("i; a", Target::Files).message, "Unknown modifier `i`");
+        assert_eq!(
+            error("a || i: b", Target::Files).span,
+            Span { start: 5, end: 6 }
+        );
+        assert!(error("depth=1", Target::History)
+            .message
+            .contains("history"));
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(
+            compiled("!type=dir && !(size>1 || depth=1)").sql,
+            r#"NOT ("f"."type" = ?1) AND NOT ("f"."length" > ?2) AND NOT ("f"."depth" = ?3)"#
+        );
+        assert_eq!(
+            compiled("not(a && b) || !!c").sql,
+            r#"((NOT (instr("f"."path", ?1) > 0) OR NOT (instr("f"."path", ?2) > 0)) OR instr("f"."path", ?3) > 0)"#
+        );
+        // Files missing from the full-text index match without the exact check
+        assert_eq!(
+            compiled("projects && !target/").sql,
+            r#"("f"."fts_id" IN (SELECT "rowid" FROM "files_trigram" WHERE "files_trigram" MATCH ?2) AND instr("f"."path", ?1) > 0) AND (NOT EXISTS (SELECT 1 FROM "files_trigram" WHERE "files_trigram" MATCH ?4 AND "rowid" = "f"."fts_id") OR NOT (instr("f"."path", ?3) > 0))"#
+        );
+        assert_eq!(
+            compiled("!name=main.rs").sql,
+            r#"NOT ("f"."path" = ?1) AND (NOT EXISTS (SELECT 1 FROM "files_fts" WHERE "files_fts" MATCH ?4 AND "rowid" = "f"."fts_id") OR NOT (substr("f"."path", -?2) = ?3))"#
+        );
+        assert_eq!(
+            compiled("!re(main)").sql,
+            r#"(NOT EXISTS (SELECT 1 FROM "files_trigram" WHERE "files_trigram" MATCH ?2 AND "rowid" = "f"."fts_id") OR NOT (regexp_name(?1, "f"."path")))"#
+        );
+        assert_eq!(
+            compile(&parse("!target/").unwrap(), &Options::new(Target::History))
+                .unwrap()
+                .sql,
+            r#"NOT (instr("f"."path", ?1) > 0)"#
+        );
+
+        assert_eq!(
+            error("a || i: b", Target::Files).message,
+            "Unknown modifier `i`"
+        );
+        let negated = "Modifier `i` cannot be used inside of negation";
+        assert_eq!(error("!(a || i: b)", Target::Files).message, negated);
+        assert_eq!(error("not(i; a)", Target::Files).message, negated);
+        assert_eq!(
+            error("i; !a", Target::Files).message,
+            "Unknown modifier `i`"
+        );
+    }
+
+    #[test]
+    fn macros() {
+        let condition = compiled("{code} && !{rust}");
+        assert_eq!(
+            condition.sql,
+            r#"(("f"."fts_id" IN (SELECT "rowid" FROM "files_fts" WHERE "files_fts" MATCH ?3) AND substr("f"."path", -?1) = ?2) AND "f"."type" = ?4 OR ("f"."fts_id" IN (SELECT "rowid" FROM "files_fts" WHERE "files_fts" MATCH ?7) AND substr("f"."path", -?5) = ?6)) AND ((NOT EXISTS (SELECT 1 FROM "files_fts" WHERE "files_fts" MATCH ?10 AND "rowid" = "f"."fts_id") OR NOT (substr("f"."path", -?8) = ?9)) OR NOT ("f"."type" = ?11))"#
+        );
+        // The same macro may be used several times, only recursion is an error
+        assert_eq!(compiled("{deep} || {deep}").params.len(), 2);
+        assert_eq!(
+            compiled("{deep}").sql,
+            r#""f"."id" IN (SELECT "file" FROM "compiled" WHERE "macro" = ?1)"#
+        );
+        assert_eq!(compiled("{deep}").params, vec![Value::Integer(7)]);
+        assert!(compiled("mtime<1d || {rust}").uses_now);
+        assert!(compiled("mtime=today").uses_now);
+        assert!(!compiled("mtime<2017-07-15 || {deep}").uses_now);
+
+        let err = error("x || {ring1}", Target::Files);
+        assert_eq!(
+            err.message,
+            "Macro `ring1` references itself: ring1 -> ring2 -> ring1 at 0..7 in macro `ring2`"
+        );
+        assert_eq!(err.span, Span { start: 5, end: 12 });
+        let err = error("{uses_broken} && a", Target::Files);
+        assert_eq!(
+            err.message,
+            "Unknown column `color` at 0..5 in macro `broken`"
+        );
+        assert_eq!(err.span, Span { start: 0, end: 13 });
+        assert_eq!(
+            error("{deep}", Target::History).to_string(),
+            "Depth is not recorded in history at 0..5 in macro `deep` at 0..6"
+        );
+        assert_eq!(
+            error("{deep 1}", Target::Files).message,
+            "Macro `deep` takes no arguments"
+        );
+    }
+
+    #[test]
+    fn macro_params() {
+        let expected = compiled(r#"size>1k && path_glob("src/**")"#);
+        assert_eq!(compiled("{large 1k src}"), expected);
+        assert_eq!(compiled("large(1k, src)"), expected);
+        assert_eq!(compiled("large(dir=src, size=1k)"), expected);
+        assert_eq!(compiled("{price 5}"), compiled("$5"));
+        assert_eq!(
+            compiled("{nested 2 a}"),
+            compiled(r#"(size>2 && path_glob("a/**")) || (size>2 && path_glob("docs/**"))"#)
+        );
+
+        let err = error("{large 1k}", Target::Files);
+        assert_eq!(err.message, "Missing argument `dir` of macro `large`");
+        assert_eq!(err.span, Span { start: 0, end: 10 });
+        let err = error("{large 1x src}", Target::Files);
+        assert_eq!(err.message, "Invalid size `1x` of parameter `size`");
+        assert_eq!(err.span, Span { start: 7, end: 9 });
+        assert_eq!(
+            error("large(1k, src, c)", Target::Files).message,
+            "Macro `large` takes 2 arguments"
+        );
+        assert_eq!(
+            error("large(1k, src, size=2)", Target::Files).message,
+            "Parameter `size` is given twice"
+        );
+        assert_eq!(
+            error("large(1k, color=2)", Target::Files).message,
+            "Macro `large` has no parameter `color`"
+        );
+        assert_eq!(
+            error("{typo 1}", Target::Files).message,
+            "Unknown parameter `$b` at 0..2 in macro `typo`"
+        );
+        assert_eq!(
+            error("unknown(1)", Target::Files).message,
+            "Unknown function `unknown`"
+        );
+
+        // Values with parameters are checked when the macro is used
+        let options = options(Target::Files);
+        for name in &["large", "nested", "mine", "kind", "mine_large"] {
+            assert!(compile_macro(name, &options).is_ok(), "{}", name);
+        }
+        assert_eq!(compiled("{mine alice}"), compiled("uid=1000"));
+        assert_eq!(
+            error("{mine bob}", Target::Files).message,
+            "Unknown user `bob` at 5..7 in macro `mine`"
+        );
+        assert_eq!(
+            error("kind(color)", Target::Files).message,
+            "Invalid value of `type`: color at 5..7 in macro `kind`"
+        );
+        for name in &["typo", "bad_column", "bad_order"] {
+            assert!(compile_macro(name, &options).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn params() {
+        let params = parse_params("size: size, dir,n:number").unwrap();
+        assert_eq!(
+            params[0],
+            Param {
+                name: "size".to_string(),
+                kind: ParamType::Size
+            }
+        );
+        assert_eq!(params[1].kind, ParamType::Text);
+        assert_eq!(params[2].kind, ParamType::Number);
+        let formatted: Vec<String> = params.iter().map(ToString::to_string).collect();
+        assert_eq!(formatted.join(", "), "size: size, dir, n: number");
+        assert!(parse_params(" ").unwrap().is_empty());
+        assert!(parse_params("a, a").is_err());
+        assert!(parse_params("a: color").is_err());
+        assert!(parse_params("a b").is_err());
+        assert!(parse_params("a,").is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        let condition = compiled(r#"re("^ma.\.c$") || path_glob(src/*.rs)"#);
+        assert_eq!(
+            condition.sql,
+            r#"(regexp_name(?1, "f"."path") OR ("f"."fts_id" IN (SELECT "rowid" FROM "files_trigram" WHERE "files_trigram" MATCH ?3) AND glob_path(?2, "f"."path")))"#
+        );
+        assert_eq!(condition.params[2], Value::Text("\"src/\"".to_string()));
+        assert!(compiled("glob(*.txt)").sql.contains("files_trigram"));
+        assert_eq!(
+            compiled(r#"path_re("a|bcd")"#).sql,
+            r#"regexp(?1, "f"."path")"#
+        );
+        let condition = compile(&parse("re(abc)").unwrap(), &options(Target::History)).unwrap();
+        assert_eq!(condition.sql, r#"regexp_name(?1, "f"."path")"#);
+
+        assert!(error("re(\"(a\")", Target::Files)
+            .message
+            .starts_with("Invalid pattern `(a`: "));
+        assert_eq!(
+            error("glob(a, b)", Target::Files).message,
+            "`glob` takes a single pattern"
+        );
+        assert_eq!(
+            error("re(a, i=1)", Target::Files).span,
+            Span { start: 6, end: 9 }
+        );
+    }
+
+    #[test]
+    fn reversed_prefixes() {
+        let prefix = |suffix: &[u8]| reversed_prefix(&MixedString::from_bytes(suffix));
+        assert_eq!(prefix(b".mkv"), "vkm");
+        assert_eq!(prefix(b"x"), "");
+        assert_eq!(prefix("\u{431}\u{435}\u{301}".as_bytes()), "\u{435}\u{301}");
+        assert_eq!(prefix(b"\x80ab"), "b");
+        assert_eq!(prefix(b"ab\x80"), "");
+    }
+}
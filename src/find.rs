@@ -1,73 +1,820 @@
 use crate::mixed::MixedString;
-use crate::model::{FileInfo, SubvolumeInfo, SubvolumeSource};
+use crate::model::{FileInfo, FileType, SubvolumeInfo, SubvolumeSource};
+use crate::prune::{PruneRules, Pruner, Verdict};
 use chrono::NaiveDateTime;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, Metadata};
 use std::io;
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::thread;
 
-use std::time::{SystemTime, UNIX_EPOCH};
 use walkdir::WalkDir;
 
-trait IntoNaive {
-    fn into_naive(self) -> NaiveDateTime;
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn timestamp(sec: i64, nanos: i64) -> NaiveDateTime {
+    // Kernel always keeps nanoseconds in [0; 1e9)
+    NaiveDateTime::from_timestamp(sec, nanos as u32)
 }
 
-impl IntoNaive for SystemTime {
-    fn into_naive(self) -> NaiveDateTime {
-        let (sec, nanos) = match self.duration_since(UNIX_EPOCH) {
-            Ok(dur) => {
-                let (sec, nanos) = (dur.as_secs(), dur.subsec_nanos());
-                #[allow(clippy::cast_possible_wrap)]
-                let sec = sec as i64;
-                (sec, nanos)
+/// Error that occurred while walking, but did not stop it
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: MixedString,
+    pub error: io::Error,
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.error)
+    }
+}
+
+fn to_mixed(path: &Path) -> MixedString {
+    MixedString::from_bytes(path.as_os_str().as_bytes())
+}
+
+pub fn to_path(path: &MixedString) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(&path.to_bytes()))
+}
+
+/// Path inside of the volume, as it is stored in the database. Root itself is an empty string
+pub fn relative(root: &Path, path: &Path) -> MixedString {
+    to_mixed(path.strip_prefix(root).unwrap_or(path))
+}
+
+/// Inverse of `relative`
+pub fn absolute(root: &Path, relative: &MixedString) -> PathBuf {
+    let bytes = relative.to_bytes();
+    if bytes.is_empty() {
+        root.to_path_buf()
+    } else {
+        root.join(OsStr::from_bytes(&bytes))
+    }
+}
+
+/// Converts metadata without following symlinks or opening the file
+pub fn file_info(path: MixedString, meta: &Metadata) -> FileInfo {
+    FileInfo {
+        filename: path,
+        permissions: meta.st_mode().into(),
+        modified: timestamp(meta.st_mtime(), meta.st_mtime_nsec()),
+        accessed: timestamp(meta.st_atime(), meta.st_atime_nsec()),
+        created: timestamp(meta.st_ctime(), meta.st_ctime_nsec()),
+        length: meta.len(),
+        user_id: meta.st_uid().into(),
+        group_id: meta.st_gid().into(),
+        filetype: meta.file_type().into(),
+    }
+}
+
+/// Metadata of the volume root, which also must be readable. Walking stops if it is not:
+/// missing root (e.g. unmounted disk) would be recorded as removal of every indexed file
+fn root_metadata(root: &Path) -> Result<Metadata, WalkError> {
+    let meta = root.symlink_metadata();
+    let readable = meta.and_then(|meta| {
+        if meta.is_dir() {
+            fs::read_dir(root)?;
+        }
+        Ok(meta)
+    });
+    readable.map_err(|error| WalkError {
+        path: to_mixed(root),
+        error,
+    })
+}
+
+/// Walks the whole tree except pruned parts. Paths are relative to `path`.
+/// Unreadable entries are skipped and reported in the returned list, unreadable root is an error
+pub fn walk(
+    path: MixedString,
+    rules: &PruneRules,
+) -> Result<(SubvolumeInfo, Vec<WalkError>), WalkError> {
+    walk_subtree(path, &MixedString::from_str(""), rules)
+}
+
+/// Same as `walk`, but only `subtree` of the volume is walked
+pub fn walk_subtree(
+    path: MixedString,
+    subtree: &MixedString,
+    rules: &PruneRules,
+) -> Result<(SubvolumeInfo, Vec<WalkError>), WalkError> {
+    let root = to_path(&path);
+    root_metadata(&root)?;
+    let start = absolute(&root, subtree);
+    let mut pruner = Pruner::new(&root, rules.clone());
+    let mut walker = WalkDir::new(&start).into_iter();
+    let mut result = HashMap::new();
+    let mut errors = Vec::new();
+    while let Some(res) = walker.next() {
+        let entry = match res {
+            Ok(entry) => entry,
+            Err(err) => {
+                let failed = to_mixed(err.path().unwrap_or(&start));
+                errors.push(WalkError {
+                    path: failed,
+                    error: err.into(),
+                });
+                continue;
             }
-            Err(e) => {
-                // unlikely but should be handled
-                let dur = e.duration();
-                let (sec, nanos) = (dur.as_secs(), dur.subsec_nanos());
-                #[allow(clippy::cast_possible_wrap)]
-                let sec = sec as i64;
-                if nanos == 0 {
-                    (-sec, 0)
-                } else {
-                    (-sec - 1, 1_000_000_000 - nanos)
-                }
+        };
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                errors.push(WalkError {
+                    path: to_mixed(entry.path()),
+                    error: err.into(),
+                });
+                continue;
             }
         };
-        NaiveDateTime::from_timestamp(sec, nanos)
+        let verdict = pruner.check(entry.path(), &meta);
+        if verdict != Verdict::Keep && entry.file_type().is_dir() {
+            walker.skip_current_dir();
+        }
+        if verdict != Verdict::Exclude {
+            let entry_path = relative(&root, entry.path());
+            let info = file_info(entry_path.clone(), &meta);
+            result.insert(entry_path, Some(info));
+        }
     }
+    errors.extend(pruner.take_errors());
+    let info = SubvolumeInfo {
+        source: SubvolumeSource::Find { path },
+        overwrite: true,
+        files: result,
+    };
+    Ok((info, errors))
 }
 
-//noinspection RsUnresolvedReference
-pub fn walk(path: MixedString) -> io::Result<SubvolumeInfo> {
-    let walker = WalkDir::new(path.to_string());
-    let mut result = HashMap::new();
-    for res in walker {
-        if let Ok(entry) = res {
-            let path = entry.path().as_os_str().as_bytes();
-            let path = MixedString::from_bytes(path);
-
-            let meta = std::fs::File::open(entry.path())?.metadata()?;
-
-            let info = FileInfo {
-                filename: path.clone(),
-                permissions: meta.permissions().mode().into(),
-                modified: meta.modified()?.into_naive(),
-                accessed: meta.accessed()?.into_naive(),
-                created: meta.created()?.into_naive(),
-                length: meta.len(),
-                user_id: meta.st_uid().into(),
-                group_id: meta.st_gid().into(),
-                filetype: entry.file_type().into(),
+/// Directories that are not read yet and number of directories that are being read now
+struct Queue {
+    dirs: Vec<PathBuf>,
+    active: usize,
+    /// Some worker panicked, others stop instead of waiting for its directories
+    panicked: bool,
+}
+
+/// Directory taken from `Queue` by a worker. Subdirectories found in it are returned
+/// to the queue on drop, which also happens if the worker panics, so nobody waits for it forever
+struct Taken<'a> {
+    queue: &'a Mutex<Queue>,
+    changed: &'a Condvar,
+    found: Vec<PathBuf>,
+}
+
+impl Drop for Taken<'_> {
+    fn drop(&mut self) {
+        // Panic while dropping during a panic would abort, so poisoning is ignored
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.dirs.append(&mut self.found);
+        queue.active -= 1;
+        queue.panicked |= thread::panicking();
+        drop(queue);
+        self.changed.notify_all();
+    }
+}
+
+/// Waits for a directory to read. `None` means that the walk is over
+fn take_dir<'a>(queue: &'a Mutex<Queue>, changed: &'a Condvar) -> Option<(PathBuf, Taken<'a>)> {
+    let mut locked = queue.lock().unwrap();
+    loop {
+        if locked.panicked {
+            return None;
+        }
+        if let Some(dir) = locked.dirs.pop() {
+            locked.active += 1;
+            let taken = Taken {
+                queue,
+                changed,
+                found: Vec::new(),
             };
-            result.insert(path, Some(info));
+            return Some((dir, taken));
+        }
+        if locked.active == 0 {
+            return None;
         }
+        locked = changed.wait(locked).unwrap();
     }
-    Ok(SubvolumeInfo {
+}
+
+/// Part of `walk_parallel` executed by every thread
+fn walk_worker(
+    root: &Path,
+    rules: &PruneRules,
+    queue: &Mutex<Queue>,
+    changed: &Condvar,
+) -> (HashMap<MixedString, Option<FileInfo>>, Vec<WalkError>) {
+    let mut pruner = Pruner::new(root, rules.clone());
+    let mut result = HashMap::new();
+    let mut errors = Vec::new();
+    while let Some((dir, mut taken)) = take_dir(queue, changed) {
+        match fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(err) => {
+                            errors.push(WalkError {
+                                path: to_mixed(&dir),
+                                error: err,
+                            });
+                            continue;
+                        }
+                    };
+                    let path = entry.path();
+                    // Does not follow symlinks
+                    let meta = match entry.metadata() {
+                        Ok(meta) => meta,
+                        Err(err) => {
+                            errors.push(WalkError {
+                                path: to_mixed(&path),
+                                error: err,
+                            });
+                            continue;
+                        }
+                    };
+                    let verdict = pruner.check(&path, &meta);
+                    if verdict == Verdict::Exclude {
+                        continue;
+                    }
+                    let mixed = relative(root, &path);
+                    result.insert(mixed.clone(), Some(file_info(mixed, &meta)));
+                    if verdict == Verdict::Keep && meta.is_dir() {
+                        taken.found.push(path);
+                    }
+                }
+            }
+            Err(err) => errors.push(WalkError {
+                path: to_mixed(&dir),
+                error: err,
+            }),
+        }
+    }
+    errors.extend(pruner.take_errors());
+    (result, errors)
+}
+
+/// Same as `walk`, but directories are read by `threads` threads simultaneously.
+/// Result does not depend on the order of reading, errors are sorted by path
+///
+/// Generated tree of 1M entries with warm inode cache is walked in about 3.3 seconds by `walk`
+/// and in 3.4-3.8 seconds with 1 to 8 threads on a single-core virtual machine, see `bench_walkers`.
+/// Threads do not pay off without several cores, one thread is slower than `walk`.
+pub fn walk_parallel(
+    path: MixedString,
+    rules: &PruneRules,
+    threads: usize,
+) -> Result<(SubvolumeInfo, Vec<WalkError>), WalkError> {
+    let root = to_path(&path);
+    let root = root.as_path();
+    let mut result = HashMap::new();
+    let mut errors = Vec::new();
+
+    let queue = Mutex::new(Queue {
+        dirs: Vec::new(),
+        active: 0,
+        panicked: false,
+    });
+    let meta = root_metadata(root)?;
+    let verdict = Pruner::new(root, rules.clone()).check(root, &meta);
+    if verdict == Verdict::Keep && meta.is_dir() {
+        queue.lock().unwrap().dirs.push(root.to_path_buf());
+    }
+    let mixed = relative(root, root);
+    result.insert(mixed.clone(), Some(file_info(mixed, &meta)));
+
+    let changed = Condvar::new();
+    let (queue, changed) = (&queue, &changed);
+    let parts: Vec<_> = thread::scope(|scope| {
+        // Every worker is started before the first one is joined
+        #[allow(clippy::needless_collect)]
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| scope.spawn(move || walk_worker(root, rules, queue, changed)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    for (files, errs) in parts {
+        result.extend(files);
+        errors.extend(errs);
+    }
+    errors.sort_by_key(|a| a.path.to_bytes());
+
+    let info = SubvolumeInfo {
         source: SubvolumeSource::Find { path },
         overwrite: true,
         files: result,
-    })
+    };
+    Ok((info, errors))
+}
+
+/// Groups indexed paths by their parent directory
+fn children_of(indexed: &HashMap<MixedString, FileInfo>) -> HashMap<Vec<u8>, Vec<MixedString>> {
+    let mut result: HashMap<Vec<u8>, Vec<MixedString>> = HashMap::new();
+    for path in indexed.keys() {
+        let bytes = path.to_bytes();
+        let parent = match bytes.iter().rposition(|&b| b == b'/') {
+            Some(pos) => bytes[..pos].to_vec(),
+            // Entries of the root
+            None if !bytes.is_empty() => Vec::new(),
+            None => continue,
+        };
+        result.entry(parent).or_default().push(path.clone());
+    }
+    result
+}
+
+/// Access time is ignored, because walking itself changes it for directories
+fn is_changed(old: &FileInfo, new: &FileInfo) -> bool {
+    old.permissions != new.permissions
+        || old.modified != new.modified
+        || old.created != new.created
+        || old.length != new.length
+        || old.user_id != new.user_id
+        || old.group_id != new.group_id
+        || old.filetype != new.filetype
+}
+
+/// Marks indexed `path` and everything inside of it as deleted
+fn remove_indexed(
+    path: &MixedString,
+    indexed: &HashMap<MixedString, FileInfo>,
+    children: &HashMap<Vec<u8>, Vec<MixedString>>,
+    result: &mut HashMap<MixedString, Option<FileInfo>>,
+) {
+    let mut stack = vec![path.clone()];
+    while let Some(path) = stack.pop() {
+        if let Some(inside) = children.get(&path.to_bytes()) {
+            stack.extend(inside.iter().cloned());
+        }
+        if indexed.contains_key(&path) {
+            result.insert(path, None);
+        }
+    }
+}
+
+/// Rescans the tree comparing it with already `indexed` state and returns only the difference.
+///
+/// Directory with the same mtime as indexed one has the same entries, so it is not read again:
+/// its indexed entries are stat'ed instead, which is much cheaper than reading the directory.
+/// That still notices files modified in place, because they do not change their directory.
+/// Unreadable root is an error, as in `walk`.
+pub fn walk_incremental(
+    path: MixedString,
+    rules: &PruneRules,
+    indexed: &HashMap<MixedString, FileInfo>,
+) -> Result<(SubvolumeInfo, Vec<WalkError>), WalkError> {
+    let root = to_path(&path);
+    root_metadata(&root)?;
+    let children = children_of(indexed);
+    let mut pruner = Pruner::new(&root, rules.clone());
+    let mut result = HashMap::new();
+    let mut errors = Vec::new();
+
+    let mut stack: Vec<PathBuf> = vec![root.clone()];
+    while let Some(current) = stack.pop() {
+        let mixed = relative(&root, &current);
+        let meta = match current.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::NotFound {
+                    remove_indexed(&mixed, indexed, &children, &mut result);
+                } else {
+                    errors.push(WalkError {
+                        path: to_mixed(&current),
+                        error: err,
+                    });
+                }
+                continue;
+            }
+        };
+        let verdict = pruner.check(&current, &meta);
+        if verdict == Verdict::Exclude {
+            remove_indexed(&mixed, indexed, &children, &mut result);
+            continue;
+        }
+
+        let info = file_info(mixed.clone(), &meta);
+        let old = indexed.get(&mixed);
+        let unchanged = old.is_some_and(|old| {
+            old.filetype == FileType::Directory && old.modified == info.modified
+        });
+        if old.is_none_or(|old| is_changed(old, &info)) {
+            result.insert(mixed.clone(), Some(info));
+        }
+
+        let inside = children.get(&mixed.to_bytes());
+        if !meta.is_dir() || verdict == Verdict::Leaf {
+            for child in inside.into_iter().flatten() {
+                remove_indexed(child, indexed, &children, &mut result);
+            }
+            continue;
+        }
+        if unchanged {
+            let entries = inside.into_iter().flatten();
+            stack.extend(entries.map(|child| absolute(&root, child)));
+            continue;
+        }
+
+        let entries = match fs::read_dir(&current) {
+            Ok(entries) => entries,
+            Err(err) => {
+                // Keep indexed contents as is
+                errors.push(WalkError {
+                    path: to_mixed(&current),
+                    error: err,
+                });
+                continue;
+            }
+        };
+        let mut present = HashSet::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    present.insert(relative(&root, &entry.path()));
+                    stack.push(entry.path());
+                }
+                Err(err) => errors.push(WalkError {
+                    path: to_mixed(&current),
+                    error: err,
+                }),
+            }
+        }
+        for child in inside.into_iter().flatten() {
+            if !present.contains(child) {
+                remove_indexed(child, indexed, &children, &mut result);
+            }
+        }
+    }
+    errors.extend(pruner.take_errors());
+
+    let info = SubvolumeInfo {
+        source: SubvolumeSource::Find { path },
+        overwrite: false,
+        files: result,
+    };
+    Ok((info, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        absolute, relative, take_dir, walk, walk_incremental, walk_parallel, walk_subtree,
+        walk_worker, Queue,
+    };
+    use crate::mixed::MixedString;
+    use crate::model::{FileInfo, FileType, SubvolumeInfo};
+    use crate::prune::PruneRules;
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::Path;
+    use std::sync::{Condvar, Mutex};
+    use std::thread;
+    use std::time::Instant;
+
+    fn mixed(path: &Path) -> MixedString {
+        MixedString::from_bytes(path.as_os_str().as_bytes())
+    }
+
+    fn key(path: &str) -> MixedString {
+        MixedString::from_bytes(path.as_bytes())
+    }
+
+    fn filetype(path: &str, files: &super::SubvolumeInfo) -> FileType {
+        files.files[&key(path)].as_ref().unwrap().filetype
+    }
+
+    #[test]
+    fn regular() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("dir")).unwrap();
+        fs::write(dir.path().join("dir/file"), b"hello").unwrap();
+
+        let (info, errors) = walk(mixed(dir.path()), &PruneRules::default()).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(info.files.len(), 3);
+        assert_eq!(filetype("dir", &info), FileType::Directory);
+        let file = info.files[&key("dir/file")].as_ref().unwrap();
+        assert_eq!(file.filetype, FileType::File);
+        assert_eq!(file.length, 5);
+    }
+
+    #[test]
+    fn special_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let fifo = dir.path().join("fifo");
+        let c_path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+
+        let socket = dir.path().join("socket");
+        let _listener = UnixListener::bind(&socket).unwrap();
+
+        let dangling = dir.path().join("dangling");
+        symlink(dir.path().join("nowhere"), &dangling).unwrap();
+
+        let (info, errors) = walk(mixed(dir.path()), &PruneRules::default()).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(info.files.len(), 4);
+        assert_eq!(filetype("fifo", &info), FileType::Fifo);
+        assert_eq!(filetype("socket", &info), FileType::Socket);
+        assert_eq!(filetype("dangling", &info), FileType::Symlink);
+    }
+
+    #[test]
+    fn unreadable_directory() {
+        if unsafe { libc::geteuid() } == 0 {
+            eprintln!("unreadable_directory is skipped: root is able to read anything");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::write(locked.join("secret"), b"").unwrap();
+        fs::write(dir.path().join("visible"), b"").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let (info, errors) = walk(mixed(dir.path()), &PruneRules::default()).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(info.files.contains_key(&key("locked")));
+        assert!(info.files.contains_key(&key("visible")));
+        assert!(!info.files.contains_key(&key("locked/secret")));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, mixed(&locked));
+    }
+
+    #[test]
+    fn missing_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let rules = PruneRules::default();
+
+        let err = walk(mixed(&missing), &rules).unwrap_err();
+        assert_eq!(err.path, mixed(&missing));
+        assert_eq!(err.error.kind(), std::io::ErrorKind::NotFound);
+        assert!(walk_parallel(mixed(&missing), &rules, 4).is_err());
+        // Otherwise everything indexed would be removed
+        let indexed = indexed(dir.path());
+        assert!(walk_incremental(mixed(&missing), &rules, &indexed).is_err());
+    }
+
+    #[test]
+    fn pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/node_modules/pkg")).unwrap();
+        fs::create_dir_all(dir.path().join("deep/er/est")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), b"").unwrap();
+
+        let rules = "exclude node_modules\nmax-depth 2".parse().unwrap();
+        let (info, errors) = walk(mixed(dir.path()), &rules).unwrap();
+        assert!(errors.is_empty());
+
+        let mut paths: Vec<String> = info.files.keys().map(MixedString::to_string).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["", "deep", "deep/er", "src", "src/main.rs"]);
+    }
+
+    #[test]
+    fn relative_paths() {
+        let root = Path::new("/mnt/data");
+        assert_eq!(relative(root, root), key(""));
+        assert_eq!(relative(root, &root.join("a/b")), key("a/b"));
+        assert_eq!(absolute(root, &key("")), root);
+        assert_eq!(absolute(root, &key("a/b")), root.join("a/b"));
+    }
+
+    #[test]
+    fn subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/skip")).unwrap();
+        fs::create_dir_all(dir.path().join("other")).unwrap();
+
+        // Rules are applied relative to the volume root
+        let rules = "exclude /a/b/skip".parse().unwrap();
+        let (info, errors) = walk_subtree(mixed(dir.path()), &key("a"), &rules).unwrap();
+        assert!(errors.is_empty());
+        let mut paths: Vec<String> = info.files.keys().map(MixedString::to_string).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["a", "a/b"]);
+    }
+
+    fn indexed(root: &Path) -> HashMap<MixedString, FileInfo> {
+        let (info, errors) = walk(mixed(root), &PruneRules::default()).unwrap();
+        assert!(errors.is_empty());
+        info.files
+            .into_iter()
+            .map(|(path, info)| (path, info.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn incremental_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/file"), b"").unwrap();
+
+        let indexed = indexed(dir.path());
+        let (delta, errors) =
+            walk_incremental(mixed(dir.path()), &PruneRules::default(), &indexed).unwrap();
+        assert!(errors.is_empty());
+        assert!(!delta.overwrite);
+        assert!(delta.files.is_empty());
+    }
+
+    #[test]
+    fn incremental_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("kept/removed/deep")).unwrap();
+        fs::write(root.join("kept/removed/deep/file"), b"").unwrap();
+        fs::write(root.join("kept/old"), b"").unwrap();
+        fs::create_dir(root.join("other")).unwrap();
+
+        let indexed = indexed(root);
+        fs::remove_dir_all(root.join("kept/removed")).unwrap();
+        fs::write(root.join("kept/new"), b"new").unwrap();
+        fs::write(root.join("other/new"), b"").unwrap();
+
+        let (delta, errors) =
+            walk_incremental(mixed(root), &PruneRules::default(), &indexed).unwrap();
+        assert!(errors.is_empty());
+
+        let removed = [
+            "kept/removed",
+            "kept/removed/deep",
+            "kept/removed/deep/file",
+        ];
+        for path in &removed {
+            assert!(delta.files[&key(path)].is_none(), "{}", path);
+        }
+        assert_eq!(delta.files[&key("kept/new")].as_ref().unwrap().length, 3);
+        assert!(delta.files[&key("other/new")].is_some());
+        // Both directories are changed
+        assert!(delta.files[&key("kept")].is_some());
+        assert!(delta.files[&key("other")].is_some());
+        assert!(!delta.files.contains_key(&key("kept/old")));
+        assert_eq!(delta.files.len(), removed.len() + 4);
+    }
+
+    #[test]
+    fn incremental_unchanged_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/edited"), b"").unwrap();
+        fs::write(root.join("dir/same"), b"").unwrap();
+
+        // Neither editing a file nor removing a phantom one changes mtime of the directory
+        let mut indexed = indexed(root);
+        let phantom = key("dir/phantom");
+        let info = indexed[&key("dir/same")].clone();
+        indexed.insert(phantom.clone(), info);
+        fs::write(root.join("dir/edited"), b"edited").unwrap();
+
+        let (delta, errors) =
+            walk_incremental(mixed(root), &PruneRules::default(), &indexed).unwrap();
+        assert!(errors.is_empty());
+        assert!(!delta.files.contains_key(&key("dir")));
+        assert_eq!(delta.files[&key("dir/edited")].as_ref().unwrap().length, 6);
+        assert!(delta.files[&phantom].is_none());
+        assert_eq!(delta.files.len(), 2);
+    }
+
+    #[test]
+    fn parallel_same_as_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for i in 0..20 {
+            let sub = root.join(format!("dir{}/sub{}", i, i % 3));
+            fs::create_dir_all(&sub).unwrap();
+            for j in 0..10 {
+                fs::write(sub.join(format!("file{j}")), vec![0; j]).unwrap();
+            }
+        }
+        fs::create_dir_all(root.join("dir0/node_modules/pkg")).unwrap();
+        symlink(root.join("dir1"), root.join("link")).unwrap();
+
+        // Reading of directory changes its access time
+        let without_atime = |info: SubvolumeInfo| -> Vec<_> {
+            let mut files: Vec<_> = info
+                .files
+                .into_iter()
+                .map(|(path, info)| {
+                    let mut info = info.unwrap();
+                    info.accessed = info.modified;
+                    (path.to_bytes(), info)
+                })
+                .collect();
+            files.sort_by(|a, b| a.0.cmp(&b.0));
+            files
+        };
+
+        let rules = "exclude node_modules".parse().unwrap();
+        let (sequential, errors) = walk(mixed(root), &rules).unwrap();
+        assert!(errors.is_empty());
+        let sequential = without_atime(sequential);
+        for threads in &[1, 2, 8] {
+            let (parallel, errors) = walk_parallel(mixed(root), &rules, *threads).unwrap();
+            assert!(errors.is_empty());
+            assert!(parallel.overwrite);
+            assert_eq!(without_atime(parallel), sequential);
+        }
+    }
+
+    #[test]
+    fn parallel_errors_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut broken = Vec::new();
+        for i in 0..10 {
+            let sub = dir.path().join(format!("dir{i}"));
+            fs::create_dir(&sub).unwrap();
+            fs::write(sub.join("file"), b"").unwrap();
+            fs::write(sub.join(".gitignore"), b"a[\n").unwrap();
+            broken.push(mixed(&sub.join(".gitignore")));
+        }
+
+        let rules = "ignore-file .gitignore".parse().unwrap();
+        let (info, errors) = walk_parallel(mixed(dir.path()), &rules, 4).unwrap();
+        assert_eq!(info.files.len(), 31);
+        let paths: Vec<_> = errors.into_iter().map(|err| err.path).collect();
+        assert_eq!(paths, broken);
+    }
+
+    #[test]
+    fn parallel_worker_panic() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = Mutex::new(Queue {
+            dirs: vec![dir.path().to_path_buf()],
+            active: 0,
+            panicked: false,
+        });
+        let changed = Condvar::new();
+        let (queue, changed) = (&queue, &changed);
+        // The other worker waits for subdirectories of the taken one
+        let (_, taken) = take_dir(queue, changed).unwrap();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            thread::scope(|scope| {
+                let waiting = scope
+                    .spawn(move || walk_worker(dir.path(), &PruneRules::default(), queue, changed));
+                scope.spawn(move || {
+                    let _taken = taken;
+                    panic!("worker failed");
+                });
+                waiting.join().unwrap()
+            })
+        }));
+        assert!(res.is_err());
+        assert!(queue.lock().unwrap().panicked);
+    }
+
+    /// Compares `walk` with `walk_parallel` on a generated tree of 100 files per directory,
+    /// number of entries is set by `BENCH_FILES` environment variable
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_walkers() {
+        let files: usize = std::env::var("BENCH_FILES")
+            .ok()
+            .and_then(|files| files.parse().ok())
+            .unwrap_or(1_000_000);
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..files / 100 {
+            let sub = dir.path().join(format!("{}/{}", i % 100, i / 100));
+            fs::create_dir_all(&sub).unwrap();
+            for j in 0..100 {
+                fs::write(sub.join(format!("file{j}.txt")), b"").unwrap();
+            }
+        }
+
+        let rules = PruneRules::default();
+        // The first walk fills the inode cache
+        let (info, errors) = walk(mixed(dir.path()), &rules).unwrap();
+        assert!(errors.is_empty());
+        let entries = info.files.len();
+        let started = Instant::now();
+        walk(mixed(dir.path()), &rules).unwrap();
+        println!("walk: {} entries in {:?}", entries, started.elapsed());
+        for threads in &[1, 2, 4, 8] {
+            let started = Instant::now();
+            let (info, _) = walk_parallel(mixed(dir.path()), &rules, *threads).unwrap();
+            assert_eq!(info.files.len(), entries);
+            println!(
+                "walk_parallel, {} threads: {:?}",
+                threads,
+                started.elapsed()
+            );
+        }
+    }
 }
@@ -0,0 +1,396 @@
+use chrono::{Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+const NANOS_IN_SECOND: i64 = 1_000_000_000;
+
+/// Timezone of dates typed by user
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Timezone {
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Timezone {
+    /// Parses `local`, `UTC` or offset like `+03:00`, `-0530` and `+3`
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "local" => return Some(Self::Local),
+            "UTC" | "utc" | "Z" => return Some(Self::Fixed(FixedOffset::east(0))),
+            _ => {}
+        }
+        let sign = match s.chars().next()? {
+            '+' => 1,
+            '-' => -1,
+            _ => return None,
+        };
+        let digits: String = s[1..].chars().filter(|&c| c != ':').collect();
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let (hours, minutes) = match digits.len() {
+            1 | 2 => (digits.as_str(), "0"),
+            4 if s.len() == 5 || s.as_bytes()[3] == b':' => digits.split_at(2),
+            _ => return None,
+        };
+        let hours: i32 = hours.parse().ok()?;
+        let minutes: i32 = minutes.parse().ok()?;
+        if minutes >= 60 {
+            return None;
+        }
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).map(Timezone::Fixed)
+    }
+
+    /// UTC time of local `date`. The earliest one is used if clocks were turned back
+    pub fn to_utc(self, date: &NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            Self::Local => Local
+                .from_local_datetime(date)
+                .earliest()
+                .map(|date| date.naive_utc()),
+            Self::Fixed(offset) => offset
+                .from_local_datetime(date)
+                .earliest()
+                .map(|date| date.naive_utc()),
+        }
+    }
+
+    /// Local time at UTC time `date`
+    pub fn local_time(self, date: &NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Self::Local => Local.from_utc_datetime(date).naive_local(),
+            Self::Fixed(offset) => offset.from_utc_datetime(date).naive_local(),
+        }
+    }
+
+    /// Local date at UTC time `now`
+    fn date(self, now: &NaiveDateTime) -> NaiveDate {
+        self.local_time(now).date()
+    }
+}
+
+/// Parses number of bytes with optional unit: `k`, `M`, `G` and `T` are powers of 1000,
+/// `KiB`, `MiB`, `GiB` and `TiB` are powers of 1024. Fractions like `1.5G` are rounded
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+pub fn parse_size(text: &str) -> Option<i64> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let multiplier: i64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return None,
+    };
+    if let Ok(number) = number.parse::<i64>() {
+        return number.checked_mul(multiplier);
+    }
+    let bytes = (number.parse::<f64>().ok()? * multiplier as f64).round();
+    if bytes.is_finite() && bytes >= 0.0 && bytes < i64::MAX as f64 {
+        Some(bytes as i64)
+    } else {
+        None
+    }
+}
+
+/// How permission bits of `perm=...` are matched, the same way as by `find -perm`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PermMatch {
+    /// `perm=mode`: permission bits are exactly the mode
+    Exact,
+    /// `perm=-mode`: all bits of the mode are set
+    All,
+    /// `perm=/mode`: any bit of the mode is set
+    Any,
+}
+
+/// Bits of `u`, `g` and `o`, including setuid, setgid and sticky ones
+const fn who_bits(who: char) -> Option<u32> {
+    match who {
+        'u' => Some(0o4700),
+        'g' => Some(0o2070),
+        'o' => Some(0o1007),
+        'a' => Some(0o7777),
+        _ => None,
+    }
+}
+
+const fn perm_bits(perm: char) -> Option<u32> {
+    match perm {
+        'r' => Some(0o444),
+        'w' => Some(0o222),
+        'x' => Some(0o111),
+        's' => Some(0o6000),
+        't' => Some(0o1000),
+        _ => None,
+    }
+}
+
+/// Applies symbolic mode like `u+x,go-w` or `a=r` to empty permissions
+fn parse_symbolic(text: &str) -> Option<u32> {
+    let mut mode = 0;
+    for clause in text.split(',') {
+        let ops = clause.find(&['+', '-', '='][..])?;
+        let (who, mut rest) = clause.split_at(ops);
+        let mut mask = 0;
+        for c in who.chars() {
+            mask |= who_bits(c)?;
+        }
+        // No one means everyone
+        if who.is_empty() {
+            mask = 0o7777;
+        }
+        while let Some(op) = rest.chars().next() {
+            let end = rest[1..]
+                .find(&['+', '-', '='][..])
+                .map_or(rest.len(), |end| end + 1);
+            let mut bits = 0;
+            for c in rest[1..end].chars() {
+                bits |= perm_bits(c)?;
+            }
+            bits &= mask;
+            match op {
+                '+' => mode |= bits,
+                '-' => mode &= !bits,
+                _ => mode = mode & !mask | bits,
+            }
+            rest = &rest[end..];
+        }
+    }
+    Some(mode)
+}
+
+/// Parses `find -perm` style mode: octal or symbolic, prefixed with `-` or `/`
+pub fn parse_perm(text: &str) -> Option<(PermMatch, u32)> {
+    let (kind, mode) = match text.as_bytes().first() {
+        Some(b'-') => (PermMatch::All, &text[1..]),
+        Some(b'/') => (PermMatch::Any, &text[1..]),
+        _ => (PermMatch::Exact, text),
+    };
+    if !mode.is_empty() && mode.chars().all(|c| c.is_digit(8)) {
+        let mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|&mode| mode <= 0o7777)?;
+        return Some((kind, mode));
+    }
+    parse_symbolic(mode).map(|mode| (kind, mode))
+}
+
+/// Interval of times `[start, end)` in nanoseconds since the epoch
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Time {
+    /// Date or moment, e.g. the whole day for `2024-01-01`
+    Absolute { start: i64, end: i64 },
+    /// Times of files of given age, e.g. `7d` is between 8 and 7 days ago
+    Age { start: i64, end: i64 },
+}
+
+fn to_nanos(date: &NaiveDateTime) -> Option<i64> {
+    date.timestamp()
+        .checked_mul(NANOS_IN_SECOND)?
+        .checked_add(i64::from(date.timestamp_subsec_nanos()))
+}
+
+/// Nanoseconds in the unit of age
+fn age_unit(unit: &str) -> Option<i64> {
+    let seconds = match unit {
+        "s" | "sec" => 1,
+        "m" | "min" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(seconds * NANOS_IN_SECOND)
+}
+
+/// Parses Unix time in seconds, `today`, `yesterday`, local date and time
+/// (`2024-01-01`, `2024-01-01T12:30` or `2024-01-01T12:30:15`) or age like `7d`.
+/// Dates are in `timezone`, ages and days are counted from UTC time `now`
+pub fn parse_time(text: &str, timezone: Timezone, now: &NaiveDateTime) -> Option<Time> {
+    if let Ok(seconds) = text.parse::<i64>() {
+        let start = seconds.checked_mul(NANOS_IN_SECOND)?;
+        let end = start.checked_add(NANOS_IN_SECOND)?;
+        return Some(Time::Absolute { start, end });
+    }
+
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    if split > 0 {
+        if let Some(unit) = age_unit(&text[split..]) {
+            let count: i64 = text[..split].parse().ok()?;
+            let now = to_nanos(now)?;
+            let start = now.checked_sub(count.checked_add(1)?.checked_mul(unit)?)?;
+            let end = now.checked_sub(count.checked_mul(unit)?)?;
+            return Some(Time::Age { start, end });
+        }
+    }
+
+    let (local, length) = match text {
+        "today" => (timezone.date(now).and_hms(0, 0, 0), Duration::days(1)),
+        "yesterday" => (
+            timezone.date(now).pred().and_hms(0, 0, 0),
+            Duration::days(1),
+        ),
+        _ => {
+            let formats = [
+                ("%Y-%m-%dT%H:%M:%S", Duration::seconds(1)),
+                ("%Y-%m-%d %H:%M:%S", Duration::seconds(1)),
+                ("%Y-%m-%dT%H:%M", Duration::minutes(1)),
+                ("%Y-%m-%d %H:%M", Duration::minutes(1)),
+            ];
+            formats
+                .iter()
+                .find_map(|(format, length)| {
+                    let date = NaiveDateTime::parse_from_str(text, format).ok()?;
+                    Some((date, *length))
+                })
+                .or_else(|| {
+                    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+                    Some((date.and_hms(0, 0, 0), Duration::days(1)))
+                })?
+        }
+    };
+    // Both ends are converted separately, because the day may be shorter or longer
+    let start = to_nanos(&timezone.to_utc(&local)?)?;
+    let end = to_nanos(&timezone.to_utc(&(local + length))?)?;
+    Some(Time::Absolute { start, end })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_perm, parse_size, parse_time, PermMatch, Time, Timezone};
+    use chrono::{Duration, FixedOffset, NaiveDate, NaiveDateTime};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("100B"), Some(100));
+        assert_eq!(parse_size("100k"), Some(100_000));
+        assert_eq!(parse_size("100M"), Some(100_000_000));
+        assert_eq!(parse_size("2GB"), Some(2_000_000_000));
+        assert_eq!(parse_size("1KiB"), Some(1024));
+        assert_eq!(parse_size("1.5MiB"), Some(1_572_864));
+        assert_eq!(parse_size("0.5k"), Some(500));
+        assert_eq!(parse_size("3TiB"), Some(3 << 40));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("1.2.3k"), None);
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+
+    #[test]
+    fn perms() {
+        assert_eq!(parse_perm("644"), Some((PermMatch::Exact, 0o644)));
+        assert_eq!(parse_perm("-4000"), Some((PermMatch::All, 0o4000)));
+        assert_eq!(parse_perm("/111"), Some((PermMatch::Any, 0o111)));
+        assert_eq!(parse_perm("u+x"), Some((PermMatch::Exact, 0o100)));
+        assert_eq!(parse_perm("-o+w"), Some((PermMatch::All, 0o002)));
+        assert_eq!(parse_perm("u=rwx,go=rx"), Some((PermMatch::Exact, 0o755)));
+        assert_eq!(parse_perm("a+r-w"), Some((PermMatch::Exact, 0o444)));
+        assert_eq!(parse_perm("+x"), Some((PermMatch::Exact, 0o111)));
+        assert_eq!(parse_perm("ug+s,+t"), Some((PermMatch::Exact, 0o7000)));
+        assert_eq!(parse_perm("u+rw,u-w"), Some((PermMatch::Exact, 0o400)));
+        assert_eq!(parse_perm("/u+w,g+w"), Some((PermMatch::Any, 0o220)));
+        assert_eq!(parse_perm("u=rw,u=r"), Some((PermMatch::Exact, 0o400)));
+        assert_eq!(parse_perm("10000"), None);
+        assert_eq!(parse_perm("888"), None);
+        assert_eq!(parse_perm("u"), None);
+        assert_eq!(parse_perm("z+x"), None);
+        assert_eq!(parse_perm("u+q"), None);
+        assert_eq!(parse_perm("-"), None);
+    }
+
+    #[test]
+    fn timezones() {
+        let fixed = |seconds| Some(Timezone::Fixed(FixedOffset::east(seconds)));
+        assert_eq!(Timezone::parse("local"), Some(Timezone::Local));
+        assert_eq!(Timezone::parse("UTC"), fixed(0));
+        assert_eq!(Timezone::parse("+03:00"), fixed(3 * 3600));
+        assert_eq!(Timezone::parse("-0530"), fixed(-(5 * 3600 + 30 * 60)));
+        assert_eq!(Timezone::parse("+3"), fixed(3 * 3600));
+        assert_eq!(Timezone::parse("3"), None);
+        assert_eq!(Timezone::parse("+03:60"), None);
+        assert_eq!(Timezone::parse("+0:300"), None);
+        assert_eq!(Timezone::parse("+99"), None);
+        assert_eq!(Timezone::parse("Europe/Paris"), None);
+    }
+
+    #[test]
+    fn times() {
+        const SECOND: i64 = 1_000_000_000;
+        const DAY: i64 = 24 * 60 * 60 * SECOND;
+        // 2024-01-10 01:00:00 UTC, which is still 2024-01-09 in UTC-2
+        let now = NaiveDate::from_ymd(2024, 1, 10).and_hms(1, 0, 0);
+        let now_nanos = now.timestamp() * SECOND;
+        let utc = Timezone::parse("UTC").unwrap();
+        let minus2 = Timezone::parse("-02:00").unwrap();
+        let time = |text, timezone| parse_time(text, timezone, &now);
+        let absolute = |date: NaiveDateTime, length| {
+            let start = date.timestamp() * SECOND;
+            Some(Time::Absolute {
+                start,
+                end: start + length,
+            })
+        };
+        let day = |d| NaiveDate::from_ymd(2024, 1, d).and_hms(0, 0, 0);
+
+        assert_eq!(
+            time("1500000000", utc),
+            Some(Time::Absolute {
+                start: 1_500_000_000 * SECOND,
+                end: 1_500_000_001 * SECOND
+            })
+        );
+        assert_eq!(time("2024-01-01", utc), absolute(day(1), DAY));
+        assert_eq!(
+            time("2024-01-01", minus2),
+            absolute(day(1) + Duration::hours(2), DAY)
+        );
+        assert_eq!(
+            time("2024-01-01T12:30", utc),
+            absolute(
+                NaiveDate::from_ymd(2024, 1, 1).and_hms(12, 30, 0),
+                60 * SECOND
+            )
+        );
+        assert_eq!(
+            time("2024-01-01 12:30:15", utc),
+            absolute(NaiveDate::from_ymd(2024, 1, 1).and_hms(12, 30, 15), SECOND)
+        );
+        assert_eq!(time("today", utc), absolute(day(10), DAY));
+        assert_eq!(time("yesterday", utc), absolute(day(9), DAY));
+        assert_eq!(
+            time("today", minus2),
+            absolute(day(9) + Duration::hours(2), DAY)
+        );
+        assert_eq!(
+            time("7d", utc),
+            Some(Time::Age {
+                start: now_nanos - 8 * DAY,
+                end: now_nanos - 7 * DAY
+            })
+        );
+        assert_eq!(
+            time("0h", minus2),
+            Some(Time::Age {
+                start: now_nanos - 3600 * SECOND,
+                end: now_nanos
+            })
+        );
+        assert_eq!(time("7", utc).map(|_| ()), Some(()));
+        assert_eq!(time("7y", utc), None);
+        assert_eq!(time("d", utc), None);
+        assert_eq!(time("2024-13-01", utc), None);
+        assert_eq!(time("99999999999", utc), None);
+        assert_eq!(time("99999999999w", utc), None);
+    }
+}
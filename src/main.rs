@@ -10,124 +10,1248 @@
     clippy::cargo
 )]
 
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
 use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use mixed::MixedString;
+use prune::PruneRules;
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
+mod accounts;
 mod btrfs;
+mod compiler;
 mod database;
 mod find;
+mod literal;
+mod matcher;
+mod migrations;
 mod mixed;
 mod model;
 mod offseted_reader;
+mod pattern;
+mod prune;
+mod query;
+mod watch;
 
-fn update(_args: &ArgMatches) {
+/// Path from `--db` or `$XDG_DATA_HOME/file_search/index.sqlite`
+fn database_path(args: &ArgMatches) -> Option<PathBuf> {
+    if let Some(path) = args.value_of_os("database") {
+        return Some(PathBuf::from(path));
+    }
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .filter(|path| Path::new(path).is_absolute())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    let Some(data_home) = data_home else {
+        eprintln!("Unable to find data directory. Use `--db`");
+        return None;
+    };
+    Some(data_home.join("file_search").join("index.sqlite"))
+}
+
+fn open_database(args: &ArgMatches) -> Option<database::Database> {
+    let db_path = database_path(args)?;
+    if !db_path.exists() {
+        eprintln!(
+            "Database {} does not exist. Use `initialize` to create it",
+            db_path.display()
+        );
+        return None;
+    }
+    match database::Database::connect(&db_path) {
+        Ok(db) => Some(db),
+        Err(err) => {
+            eprintln!("{err}");
+            None
+        }
+    }
+}
+
+fn initialize(args: &ArgMatches) {
+    let Some(db_path) = database_path(args) else {
+        return;
+    };
+    if db_path.exists() {
+        if !args.is_present("force") {
+            eprintln!(
+                "Database {} already exists. Use `--force` to overwrite it",
+                db_path.display()
+            );
+            return;
+        }
+        if let Err(err) = database::Database::remove(&db_path) {
+            eprintln!("{}: {}", db_path.display(), err);
+            return;
+        }
+    }
+    if let Some(parent) = db_path.parent() {
+        if let Err(err) = std::fs::create_dir_all(parent) {
+            eprintln!("{}: {}", parent.display(), err);
+            return;
+        }
+    }
+    // Schema is created by migrations
+    match database::Database::connect(&db_path) {
+        Ok(_) => println!("Initialized {}", db_path.display()),
+        Err(err) => {
+            eprintln!("{err}");
+            // Half-initialized database is useless
+            let _ = database::Database::remove(&db_path);
+        }
+    }
+}
+
+fn update_btrfs(args: &ArgMatches) {
+    let Some(mut db) = open_database(args) else {
+        return;
+    };
+    let Some(precompiled) = load_precompiled(args, &db) else {
+        return;
+    };
     let stdin = std::io::stdin();
     let mut reader = stdin.lock();
     let settings = btrfs::parser::Settings {
         bypass_errors: true,
     };
-    let parser = btrfs::parser::Parser::new(settings);
-    match parser.parse(&mut reader) {
-        Ok(res) => {
-            let _out = std::fs::File::create("./ouput.json").unwrap();
-            println!("{}", res.len());
-        }
+    // Files changed by incremental stream are loaded from the parent snapshot
+    let parser = btrfs::parser::Parser::with_lookup(settings, &mut db);
+    let subvolumes = match parser.parse(&mut reader) {
+        Ok(res) => res,
         Err(err) => {
             eprintln!("{}", err);
+            return;
+        }
+    };
+    let changed: usize = subvolumes.iter().map(|subvol| subvol.files.len()).sum();
+    match db.insert_data(subvolumes, &precompiled) {
+        Ok(()) => println!("{changed} changed"),
+        Err(err) => eprintln!("{err}"),
+    }
+}
+
+/// Rescans registered `Find` volumes
+fn update_find(args: &ArgMatches) {
+    let Some(mut db) = open_database(args) else {
+        return;
+    };
+    let volumes = match db.find_volumes(model::VolumeType::Find) {
+        Ok(volumes) => volumes,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    let Some(precompiled) = load_precompiled(args, &db) else {
+        return;
+    };
+    let only: Option<Vec<&OsStr>> = args.values_of_os("subvolume").map(Iterator::collect);
+    let threads: usize = match args.value_of("threads").unwrap_or("1").parse() {
+        Ok(threads) if threads > 0 => threads,
+        _ => {
+            eprintln!("Number of threads must be a positive integer");
+            return;
         }
     };
+
+    for volume in volumes {
+        let bytes = volume.mount.to_bytes();
+        if let Some(only) = &only {
+            if !only.iter().any(|path| path.as_bytes() == &bytes[..]) {
+                continue;
+            }
+        }
+        let rules: PruneRules = match volume.settings.parse() {
+            Ok(rules) => rules,
+            Err(err) => {
+                eprintln!("Invalid settings of {}: {}", volume.path, err);
+                continue;
+            }
+        };
+
+        let walked = if args.is_present("full") {
+            if threads > 1 {
+                find::walk_parallel(volume.mount.clone(), &rules, threads)
+            } else {
+                find::walk(volume.mount.clone(), &rules)
+            }
+        } else {
+            match db.load_files(volume.id, &MixedString::from_str("")) {
+                Ok(indexed) => find::walk_incremental(volume.mount.clone(), &rules, &indexed),
+                Err(err) => {
+                    eprintln!("{err}");
+                    continue;
+                }
+            }
+        };
+        // Nothing is changed if the root is unavailable, e.g. the disk is not mounted
+        let (info, errors) = match walked {
+            Ok(walked) => walked,
+            Err(err) => {
+                eprintln!("Volume {} is not updated: {}", volume.mount, err.error);
+                continue;
+            }
+        };
+        for err in errors {
+            eprintln!("{err}");
+        }
+        let changed = info.files.len();
+        match db.insert_data(vec![info], &precompiled) {
+            Ok(()) => println!("{}: {} changed", volume.mount, changed),
+            Err(err) => eprintln!("{}: {}", volume.mount, err),
+        }
+    }
 }
 
-fn fallback(args: &ArgMatches) {
-    dbg!(args);
+fn update(args: &ArgMatches) {
+    if args.is_present("pipe") {
+        update_btrfs(args);
+    } else {
+        update_find(args);
+    }
 }
 
-#[allow(clippy::match_same_arms)]
-fn main() {
-    let matches = App::new("File search")
-        .version(crate_version!())
-        .about("Find any file by name in your btrfs subvolumes")
-        .arg(Arg::with_name("database")
-            .short("d")
-            .long("db")
-            .takes_value(true)
-            .global(true)
-            .help("Path to database"))
-        .setting(AppSettings::SubcommandRequiredElseHelp)
-        .global_settings(&[
-            AppSettings::ColoredHelp,
-            AppSettings::VersionlessSubcommands
-        ])
-        .subcommand(SubCommand::with_name("initialize")
-            .about("Initializes empty database"))
-        .subcommand(SubCommand::with_name("update")
-            .about("Reads stream from btrfs send and updates the database")
-            .arg(Arg::with_name("pipe")
+/// Registered `Find` volumes with their prune rules. Only `path` arguments are watched, if given
+fn watch_roots(args: &ArgMatches, db: &database::Database) -> Option<Vec<(PathBuf, PruneRules)>> {
+    let volumes = match db.find_volumes(model::VolumeType::Find) {
+        Ok(volumes) => volumes,
+        Err(err) => {
+            eprintln!("{err}");
+            return None;
+        }
+    };
+    let mut only = Vec::new();
+    for path in args.values_of_os("path").into_iter().flatten() {
+        let canonical = match std::fs::canonicalize(path) {
+            Ok(canonical) => canonical,
+            Err(err) => {
+                eprintln!("{}: {}", path.to_string_lossy(), err);
+                return None;
+            }
+        };
+        let bytes = canonical.as_os_str().as_bytes();
+        if !volumes
+            .iter()
+            .any(|volume| volume.mount.to_bytes() == bytes)
+        {
+            eprintln!(
+                "Directory {} is not added. Use `subvolume add` first",
+                canonical.display()
+            );
+            return None;
+        }
+        only.push(canonical);
+    }
+
+    let mut roots = Vec::new();
+    for volume in volumes {
+        let path = find::to_path(&volume.mount);
+        if !only.is_empty() && !only.contains(&path) {
+            continue;
+        }
+        match volume.settings.parse() {
+            Ok(rules) => roots.push((path, rules)),
+            Err(err) => {
+                eprintln!("Invalid settings of {}: {}", volume.path, err);
+                return None;
+            }
+        }
+    }
+    Some(roots)
+}
+
+fn watch(args: &ArgMatches) {
+    let debounce = match args.value_of("debounce").unwrap_or("2000").parse() {
+        Ok(ms) => std::time::Duration::from_millis(ms),
+        Err(err) => {
+            eprintln!("Invalid debounce interval: {err}");
+            return;
+        }
+    };
+
+    let Some(mut db) = open_database(args) else {
+        return;
+    };
+    let Some(roots) = watch_roots(args, &db) else {
+        return;
+    };
+    if roots.is_empty() {
+        eprintln!("Nothing to watch. Add some directories using `subvolume add`");
+        return;
+    }
+    let Some(precompiled) = load_precompiled(args, &db) else {
+        return;
+    };
+
+    let settings = watch::Settings { debounce };
+    let res = watch::Watcher::new(settings, roots)
+        .map_err(watch::Error::from)
+        .and_then(|mut watcher| watcher.run(&mut db, &precompiled, |err| eprintln!("{err}")));
+    if let Err(err) = res {
+        eprintln!("{err}");
+    }
+}
+
+/// Volume registered at `path`, which is either its mount point or UUID of subvolume
+fn find_volume(db: &database::Database, path: &OsStr) -> Option<database::Volume> {
+    let volumes = match db.volumes() {
+        Ok(volumes) => volumes,
+        Err(err) => {
+            eprintln!("{err}");
+            return None;
+        }
+    };
+    let canonical = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let found = volumes.into_iter().find(|volume| {
+        let mount = volume.mount.to_bytes();
+        mount == path.as_bytes()
+            || mount == canonical.as_os_str().as_bytes()
+            || (volume.volume_type == model::VolumeType::Btrfs
+                && volume.path.to_bytes() == path.as_bytes())
+    });
+    if found.is_none() {
+        eprintln!("Subvolume {} is not added", path.to_string_lossy());
+    }
+    found
+}
+
+fn subvolume_add(args: &ArgMatches) {
+    let Some(path) = args.value_of_os("path") else {
+        eprintln!("No path specified");
+        return;
+    };
+    let path = match std::fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("{}: {}", path.to_string_lossy(), err);
+            return;
+        }
+    };
+    let uuid = match btrfs::detect::subvolume_uuid(&path) {
+        Ok(uuid) => uuid,
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            return;
+        }
+    };
+    let rules = PruneRules {
+        exclude: args
+            .values_of("exclude")
+            .map_or_else(Vec::new, |values| values.map(str::to_string).collect()),
+        ignore_files: args
+            .values_of("ignore-file")
+            .map_or_else(Vec::new, |values| values.map(str::to_string).collect()),
+        one_file_system: args.is_present("one-file-system"),
+        max_depth: match args.value_of("max-depth").map(str::parse).transpose() {
+            Ok(depth) => depth,
+            Err(err) => {
+                eprintln!("Invalid depth: {err}");
+                return;
+            }
+        },
+    };
+    // Validates patterns
+    let settings = rules.to_string();
+    if let Err(err) = settings.parse::<PruneRules>() {
+        eprintln!("{err}");
+        return;
+    }
+
+    let Some(mut db) = open_database(args) else {
+        return;
+    };
+    let mount = MixedString::from_bytes(path.as_os_str().as_bytes());
+    let existing = match db.volumes() {
+        Ok(volumes) => volumes,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    if existing.iter().any(|volume| volume.mount == mount) {
+        eprintln!("Subvolume {mount} is already added");
+        return;
+    }
+    let (volume_type, path, settings) = uuid.as_ref().map_or_else(
+        || (model::VolumeType::Find, mount.clone(), settings.as_str()),
+        // Contents of subvolume are read from send stream, so walk settings are not needed
+        |uuid| (model::VolumeType::Btrfs, MixedString::from_str(uuid), ""),
+    );
+    let res = db.add_volume(volume_type, &path, &mount, settings);
+    let res = res.and_then(|id| {
+        if args.is_present("history") {
+            db.set_history(id, true)
+        } else {
+            Ok(())
+        }
+    });
+    match (res, uuid) {
+        (Ok(()), Some(uuid)) => println!("Added btrfs subvolume {mount} ({uuid})"),
+        (Ok(()), None) => println!("Added directory {mount}"),
+        (Err(err), _) => eprintln!("{err}"),
+    }
+}
+
+fn subvolume_remove(args: &ArgMatches) {
+    let Some(path) = args.value_of_os("path") else {
+        eprintln!("No path specified");
+        return;
+    };
+    let Some(mut db) = open_database(args) else {
+        return;
+    };
+    let Some(volume) = find_volume(&db, path) else {
+        return;
+    };
+    match db.remove_volume(volume.id) {
+        Ok(files) => println!("Removed {} with {} files", volume.mount, files),
+        Err(err) => eprintln!("{err}"),
+    }
+}
+
+fn subvolume_list(args: &ArgMatches) {
+    let Some(db) = open_database(args) else {
+        return;
+    };
+    let (volumes, counts) = match db.volumes().and_then(|v| Ok((v, db.count_files()?))) {
+        Ok(res) => res,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    println!("UUID\tPATH\tFILES\tUPDATED");
+    for volume in volumes {
+        let uuid = match volume.volume_type {
+            model::VolumeType::Btrfs => volume.path.to_string(),
+            model::VolumeType::Find => "-".to_string(),
+        };
+        let updated = volume.updated.map_or_else(
+            || "never".to_string(),
+            |updated| {
+                Local
+                    .from_utc_datetime(&updated)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            },
+        );
+        let files = counts.get(&volume.id).copied().unwrap_or(0);
+        println!("{}\t{}\t{}\t{}", uuid, volume.mount, files, updated);
+    }
+}
+
+/// Date `YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS` in `timezone`, converted to UTC
+fn parse_date(date: &str, timezone: literal::Timezone) -> Option<NaiveDateTime> {
+    let local = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%Y-%m-%d").map(|date| date.and_hms(0, 0, 0)))
+        .ok()?;
+    timezone.to_utc(&local)
+}
+
+/// Files matching `condition` in btrfs snapshot with UUID `as_of`,
+/// or in the last snapshots of every volume with history recorded before date `as_of`
+fn search_as_of(
+    db: &database::Database,
+    as_of: &str,
+    timezone: literal::Timezone,
+    condition: &compiler::Condition,
+) -> Option<Vec<(i64, model::FileInfo)>> {
+    let snapshots = if let Some(uuid) = model::parse_uuid(as_of) {
+        match db.snapshot(uuid) {
+            Ok(Some(snapshot)) => vec![snapshot],
+            Ok(None) => {
+                eprintln!("History of snapshot {as_of} is not recorded");
+                return None;
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                return None;
+            }
+        }
+    } else {
+        let Some(date) = parse_date(as_of, timezone) else {
+            eprintln!(
+                "Invalid snapshot or date: {as_of}. Use UUID, `YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS`"
+            );
+            return None;
+        };
+        match db.snapshots_at(date) {
+            Ok(snapshots) => snapshots,
+            Err(err) => {
+                eprintln!("{err}");
+                return None;
+            }
+        }
+    };
+
+    let mut found = Vec::new();
+    for (volume, transid) in snapshots {
+        match db.search_history(volume, transid, condition) {
+            Ok(files) => found.extend(files),
+            Err(err) => {
+                eprintln!("{err}");
+                return None;
+            }
+        }
+    }
+    Some(found)
+}
+
+/// Human-readable value of SQL parameter
+fn format_param(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(num) => num.to_string(),
+        Value::Real(num) => num.to_string(),
+        Value::Text(text) => format!("{text:?}"),
+        Value::Blob(bytes) => format!("{:?}", MixedString::from_bytes(bytes).to_string()),
+    }
+}
+
+/// Prints SQL of `condition` with its parameters and the plan of the query
+fn explain(db: &database::Database, condition: &compiler::Condition) {
+    let (sql, plan) = match db.explain(condition) {
+        Ok(explained) => explained,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    println!("\nsql\n{sql}");
+    for (i, param) in condition.params.iter().enumerate() {
+        println!("  ?{} = {}", i + 1, format_param(param));
+    }
+    println!("\nplan");
+    for step in plan {
+        println!("  {step}");
+    }
+}
+
+/// Users and groups from `--passwd-file` and `--group-file`, or from `/etc/passwd`
+/// and `/etc/group`. Missing default files mean that there are no names
+fn load_accounts(args: &ArgMatches) -> Option<accounts::Accounts> {
+    let read = |arg: &str, default: &str| {
+        let Some(path) = args.value_of_os(arg) else {
+            return Some(std::fs::read_to_string(default).unwrap_or_default());
+        };
+        match std::fs::read_to_string(path) {
+            Ok(content) => Some(content),
+            Err(err) => {
+                eprintln!("Can't read {}: {}", Path::new(path).display(), err);
+                None
+            }
+        }
+    };
+    let passwd = read("passwd-file", "/etc/passwd")?;
+    let group = read("group-file", "/etc/group")?;
+    Some(accounts::Accounts::parse(&passwd, &group))
+}
+
+/// File type and permissions as printed by `ls -l`, e.g. `drwxr-xr-x`
+fn format_mode(filetype: model::FileType, mode: u64) -> String {
+    let mut res = String::with_capacity(10);
+    res.push(match filetype {
+        model::FileType::File => '-',
+        model::FileType::Directory => 'd',
+        model::FileType::Symlink => 'l',
+        model::FileType::BlockDevice => 'b',
+        model::FileType::CharDevice => 'c',
+        model::FileType::Fifo => 'p',
+        model::FileType::Socket => 's',
+        model::FileType::Unknown => '?',
+    });
+    // Special bit replaces execute bit of its class
+    let classes = [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')];
+    for &(shift, special, mark) in &classes {
+        let bits = mode >> shift;
+        res.push(if bits & 0o4 == 0 { '-' } else { 'r' });
+        res.push(if bits & 0o2 == 0 { '-' } else { 'w' });
+        res.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => mark,
+            (false, true) => mark.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    res
+}
+
+/// `ls -l` style line: mode, owner, group, size, modification time and path.
+/// Owner and group are numeric unless `accounts` are given
+fn long_line(
+    info: &model::FileInfo,
+    path: &MixedString,
+    accounts: Option<&accounts::Accounts>,
+    timezone: literal::Timezone,
+) -> String {
+    let user = accounts
+        .and_then(|accounts| accounts.user_name(info.user_id))
+        .map_or_else(|| info.user_id.to_string(), str::to_string);
+    let group = accounts
+        .and_then(|accounts| accounts.group_name(info.group_id))
+        .map_or_else(|| info.group_id.to_string(), str::to_string);
+    format!(
+        "{} {:>8} {:>8} {:>12} {} {}",
+        format_mode(info.filetype, info.permissions),
+        user,
+        group,
+        info.length,
+        timezone.local_time(&info.modified).format("%Y-%m-%d %H:%M"),
+        path
+    )
+}
+
+/// Words of `query` argument joined by spaces. Invalid UTF-8 is kept as `\u{xx}` escapes
+/// and restored by the parser
+fn query_text(args: &ArgMatches) -> Option<String> {
+    let Some(words) = args.values_of_os("query") else {
+        eprintln!("No query specified");
+        return None;
+    };
+    let text = words
+        .map(OsStrExt::as_bytes)
+        .collect::<Vec<_>>()
+        .join(&b' ');
+    Some(MixedString::from_bytes(&text).to_string())
+}
+
+/// All macros by their names
+fn load_macros(db: &database::Database) -> Option<HashMap<String, compiler::Macro>> {
+    let macros = match db.macros() {
+        Ok(macros) => macros,
+        Err(err) => {
+            eprintln!("{err}");
+            return None;
+        }
+    };
+    let mut parsed = HashMap::new();
+    for item in macros {
+        let definition = query::parse(&item.query)
+            .map_err(|err| err.to_string())
+            .and_then(|query| Ok((query, compiler::parse_params(&item.params)?)));
+        match definition {
+            Ok((query, params)) => {
+                let compiled = if item.precompiled {
+                    Some(item.id)
+                } else {
+                    None
+                };
+                let definition = compiler::Macro {
+                    query,
+                    compiled,
+                    params,
+                };
+                parsed.insert(item.name, definition);
+            }
+            Err(err) => {
+                eprintln!("Invalid macro `{}`: {}", item.name, err);
+                return None;
+            }
+        }
+    }
+    Some(parsed)
+}
+
+/// Conditions of precompiled macros in order of creation, so every macro is evaluated
+/// after macros it references
+fn load_precompiled(
+    args: &ArgMatches,
+    db: &database::Database,
+) -> Option<Vec<database::Precompiled>> {
+    let accounts = load_accounts(args)?;
+    let macros = load_macros(db)?;
+    let options = compiler::Options {
+        accounts,
+        macros,
+        ..compiler::Options::new(compiler::Target::Files)
+    };
+    let mut ids: Vec<(i64, &str)> = options
+        .macros
+        .iter()
+        .filter_map(|(name, item)| item.compiled.map(|id| (id, name.as_str())))
+        .collect();
+    ids.sort_unstable();
+    let mut precompiled = Vec::with_capacity(ids.len());
+    for (id, name) in ids {
+        let query = &options.macros[name].query;
+        let compiled = compiler::compile(query, &options)
+            .and_then(|condition| Ok((condition, matcher::Matcher::new(query, &options)?)));
+        match compiled {
+            Ok((condition, matcher)) => precompiled.push(database::Precompiled {
+                id,
+                condition,
+                matcher,
+            }),
+            Err(err) => {
+                eprintln!("Invalid macro `{name}`: {err}");
+                return None;
+            }
+        }
+    }
+    Some(precompiled)
+}
+
+/// Prints indexed paths matching the query
+fn query(args: &ArgMatches) {
+    let Some(text) = query_text(args) else {
+        return;
+    };
+    let parsed = match query::parse(&text) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    if args.is_present("explain") {
+        print!("{parsed}");
+    }
+    let Some(db) = open_database(args) else {
+        return;
+    };
+    let Some(macros) = load_macros(&db) else {
+        return;
+    };
+    // History has neither full-text indices nor some columns
+    let target = if args.is_present("as-of") {
+        compiler::Target::History
+    } else {
+        compiler::Target::Files
+    };
+    let Some(timezone) = query_timezone(args) else {
+        return;
+    };
+    let Some(accounts) = load_accounts(args) else {
+        return;
+    };
+    let options = compiler::Options {
+        timezone,
+        accounts,
+        macros,
+        ..compiler::Options::new(target)
+    };
+    let condition = match compiler::compile(&parsed, &options) {
+        Ok(condition) => condition,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    if args.is_present("explain") {
+        explain(&db, &condition);
+        return;
+    }
+    let Some(volumes) = query_volumes(args, &db) else {
+        return;
+    };
+    let found = match args.value_of("as-of") {
+        Some(as_of) => match search_as_of(&db, as_of, timezone, &condition) {
+            Some(found) => found,
+            None => return,
+        },
+        None => match db.search(&condition) {
+            Ok(found) => found,
+            Err(err) => {
+                eprintln!("{err}");
+                return;
+            }
+        },
+    };
+    let accounts = if args.is_present("names") {
+        Some(&options.accounts)
+    } else {
+        None
+    };
+    print_found(args, found, &volumes, accounts, timezone);
+}
+
+/// Timezone of `--timezone`, local by default
+fn query_timezone(args: &ArgMatches) -> Option<literal::Timezone> {
+    let Some(timezone) = args.value_of("timezone") else {
+        return Some(literal::Timezone::Local);
+    };
+    let parsed = literal::Timezone::parse(timezone);
+    if parsed.is_none() {
+        eprintln!("Invalid timezone: {timezone}. Use `local`, `UTC` or offset like `+03:00`");
+    }
+    parsed
+}
+
+/// Volumes of `--volume` arguments or all of them, ordered by id
+fn query_volumes(args: &ArgMatches, db: &database::Database) -> Option<Vec<database::Volume>> {
+    let mut volumes = match args.values_of_os("volume") {
+        Some(paths) => paths
+            .map(|path| find_volume(db, path))
+            .collect::<Option<Vec<_>>>()?,
+        None => match db.volumes() {
+            Ok(volumes) => volumes,
+            Err(err) => {
+                eprintln!("{err}");
+                return None;
+            }
+        },
+    };
+    volumes.sort_by_key(|volume| volume.id);
+    Some(volumes)
+}
+
+/// Prints `found` files of `volumes`. Owners are printed by `accounts` names if they are given
+fn print_found(
+    args: &ArgMatches,
+    found: Vec<(i64, model::FileInfo)>,
+    volumes: &[database::Volume],
+    accounts: Option<&accounts::Accounts>,
+    timezone: literal::Timezone,
+) {
+    let mount = args
+        .value_of_os("mount")
+        .map(|mount| MixedString::from_bytes(mount.as_bytes()));
+    let group = args.is_present("group");
+    let long = args.is_present("long");
+    let mut current = None;
+    for (volume_id, info) in found {
+        let volume = match volumes.binary_search_by_key(&volume_id, |volume| volume.id) {
+            Ok(idx) => &volumes[idx],
+            Err(_) => continue,
+        };
+        // Results are ordered by volume
+        if group && current != Some(volume_id) {
+            if current.is_some() {
+                println!();
+            }
+            println!("{}:", volume.mount);
+            current = Some(volume_id);
+        }
+        let path = volume.full_path(&info.filename, mount.as_ref());
+        if long {
+            println!("{}", long_line(&info, &path, accounts, timezone));
+        } else {
+            println!("{path}");
+        }
+    }
+}
+
+/// Macro names are words that do not need quoting in `{name}`
+fn is_macro_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Name and parameters of macro declared as `name` or `name(params)`
+fn parse_declaration(text: &str) -> Result<(&str, Vec<compiler::Param>), String> {
+    let (name, params) = match text.find('(') {
+        Some(pos) => match text[pos + 1..].strip_suffix(')') {
+            Some(params) => (text[..pos].trim(), compiler::parse_params(params)?),
+            None => return Err(format!("Parameters of `{text}` are not closed")),
+        },
+        None => (text, Vec::new()),
+    };
+    if !is_macro_name(name) {
+        return Err(format!(
+            "Invalid macro name `{name}`. Use letters, digits, `_`, `-` and `.`"
+        ));
+    }
+    if compiler::FUNCTIONS.contains(&name) {
+        return Err(format!("`{name}` is a built-in function"));
+    }
+    Ok((name, params))
+}
+
+fn macros_add(args: &ArgMatches) {
+    let (name, params) = match args.value_of("name").map(parse_declaration) {
+        Some(Ok(declaration)) => declaration,
+        Some(Err(err)) => {
+            eprintln!("{err}");
+            return;
+        }
+        None => {
+            eprintln!("No name specified");
+            return;
+        }
+    };
+    let precompile = args.is_present("precompile");
+    if precompile && !params.is_empty() {
+        eprintln!("Macros with parameters can not be precompiled");
+        return;
+    }
+    let Some(text) = query_text(args) else {
+        return;
+    };
+    let parsed = match query::parse(&text) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    let Some(mut db) = open_database(args) else {
+        return;
+    };
+    let Some(mut macros) = load_macros(&db) else {
+        return;
+    };
+    if macros.contains_key(name) {
+        eprintln!("Macro `{name}` already exists. Remove it first");
+        return;
+    }
+    let Some(accounts) = load_accounts(args) else {
+        return;
+    };
+    let declared: Vec<String> = params.iter().map(ToString::to_string).collect();
+    // The macro is known while it is checked, so references to itself are found
+    let definition = compiler::Macro {
+        query: parsed,
+        compiled: None,
+        params,
+    };
+    macros.insert(name.to_string(), definition);
+    let options = compiler::Options {
+        accounts,
+        macros,
+        ..compiler::Options::new(compiler::Target::Files)
+    };
+    let condition = match compiler::compile_macro(name, &options) {
+        Ok(condition) => condition,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    if precompile && condition.uses_now {
+        eprintln!("Precompiled macro can not depend on the current time, e.g. on ages or `today`");
+        return;
+    }
+    let precompiled = if precompile { Some(&condition) } else { None };
+    match db.add_macro(name, &declared.join(", "), &text, precompiled) {
+        Ok(_) => println!("Added macro `{name}`"),
+        Err(err) => eprintln!("{err}"),
+    }
+}
+
+fn macros_remove(args: &ArgMatches) {
+    let Some(name) = args.value_of("name") else {
+        eprintln!("No name specified");
+        return;
+    };
+    let Some(db) = open_database(args) else {
+        return;
+    };
+    let Some(macros) = load_macros(&db) else {
+        return;
+    };
+    let mut users: Vec<&str> = macros
+        .iter()
+        .filter(|(other, item)| {
+            let expr = &item.query.expr;
+            *other != name && (expr.macros().contains(&name) || expr.functions().contains(&name))
+        })
+        .map(|(other, _)| other.as_str())
+        .collect();
+    if !users.is_empty() {
+        users.sort_unstable();
+        eprintln!("Macro `{}` is used by `{}`", name, users.join("`, `"));
+        return;
+    }
+    match db.remove_macro(name) {
+        Ok(true) => println!("Removed macro `{name}`"),
+        Ok(false) => eprintln!("Unknown macro `{name}`"),
+        Err(err) => eprintln!("{err}"),
+    }
+}
+
+fn macros_list(args: &ArgMatches) {
+    let Some(db) = open_database(args) else {
+        return;
+    };
+    let macros = match db.macros() {
+        Ok(macros) => macros,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    println!("NAME\tPRECOMPILED\tQUERY");
+    for item in macros {
+        let precompiled = if item.precompiled { "yes" } else { "no" };
+        let name = if item.params.is_empty() {
+            item.name
+        } else {
+            format!("{}({})", item.name, item.params)
+        };
+        println!("{}\t{}\t{}", name, precompiled, item.query);
+    }
+}
+
+fn update_command() -> App<'static, 'static> {
+    SubCommand::with_name("update")
+        .about("Reads stream from btrfs send or rescans directories and updates the database")
+        .arg(
+            Arg::with_name("pipe")
                 .long("pipe")
                 .short("p")
-                .help("Read stream from stdin"))
-            .arg(Arg::with_name("snapshot")
+                .help("Read stream from stdin"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
                 .conflicts_with("pipe")
                 .long("snapshot")
                 .short("s")
-                .help("Path to snapshots. Conflicts with `pipe`"))
-            .arg(Arg::with_name("subvolume")
-                .help("Update only specified subvolumes")))
-        .subcommand(SubCommand::with_name("query")
-            .about("Find files matching the query")
-            .after_help("Query language:")
-            .arg(Arg::with_name("query")
+                .help("Path to snapshots. Conflicts with `pipe`"),
+        )
+        .arg(
+            Arg::with_name("full")
+                .conflicts_with("pipe")
+                .long("full")
+                .short("f")
+                .help("Read every directory again instead of only changed ones"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .conflicts_with("pipe")
+                .long("threads")
+                .short("j")
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of threads reading directories. Used only with `full`"),
+        )
+        .arg(
+            Arg::with_name("subvolume")
                 .multiple(true)
-                .help("Your query")))
-        .subcommand(SubCommand::with_name("macros")
-            .about("Manage macroses")
-            .setting(AppSettings::SubcommandRequiredElseHelp)
-            .subcommand(SubCommand::with_name("add")
-                .about("Add new macro")
-                .after_help("Precompiled macroses are working faster, but they increase the size of the database")
-                .arg(Arg::with_name("precompile")
-                    .long("precompile")
-                    .short("c")
-                    .help("Precompile this macro"))
-                .arg(Arg::with_name("name")
-                    .help("Name of this macro"))
-                .arg(Arg::with_name("query")
-                    .multiple(true)
-                    .help("Query. See `query` subcommand for help")))
-            .subcommand(SubCommand::with_name("remove")
-                .about("Remove macro")
-                .arg(Arg::with_name("name")))
-            .subcommand(SubCommand::with_name("list")
-                .about("List all macroses")))
-        .subcommand(SubCommand::with_name("subvolume")
-            .about("Manage indexed subvolumes")
-            .setting(AppSettings::SubcommandRequiredElseHelp)
-            .subcommand(SubCommand::with_name("add")
-                .about("Add new subvolume")
-                .arg(Arg::with_name("path")
-                    .help("Path to subvolume")))
-            .subcommand(SubCommand::with_name("remove")
-                .about("Remove subvolume")
-                .arg(Arg::with_name("path")))
-            .subcommand(SubCommand::with_name("list")
-                .about("List all subvolumes")))
-        .get_matches();
+                .help("Update only specified subvolumes"),
+        )
+}
 
+fn watch_command() -> App<'static, 'static> {
+    SubCommand::with_name("watch")
+        .about("Watches directories using inotify and keeps the database up to date")
+        .arg(
+            Arg::with_name("debounce")
+                .long("debounce")
+                .short("t")
+                .takes_value(true)
+                .default_value("2000")
+                .help("Milliseconds to collect changes before committing them"),
+        )
+        .arg(
+            Arg::with_name("path")
+                .multiple(true)
+                .help("Watch only specified directories instead of all registered ones"),
+        )
+}
+
+fn query_command() -> App<'static, 'static> {
+    SubCommand::with_name("query")
+    .about("Find files matching the query")
+    .after_help("Prints every indexed path matching the query. A term matches paths containing it \
+        as a substring, a term starting with `*` matches paths ending with the rest of it, e.g. `*.mkv`.\n\
+        `path=...` and `name=...` match the whole path or its last component. \
+        `mode` (octal permissions without the file type), `uid`, `gid` and `type` (file, dir, symlink, ...) are compared the same way.\n\
+        `size` (`length`), `depth`, `atime`, `mtime` and `ctime` can be compared with `<`, `<=`, `>`, `>=` too. \
+        Sizes may have units: `k`, `M`, `G`, `T` or `KiB`, `MiB`, `GiB`, `TiB`, e.g. `size>100M`. \
+        Times are Unix seconds, dates like `2024-01-01` or `2024-01-01T12:30`, `today`, `yesterday`, \
+        or ages in `s`, `m`, `h`, `d`, `w`: `mtime<7d` matches files modified during the last 7 days.\n\
+        `user=...` and `group=...` take names or numeric IDs. `perm=...` takes mode like `find -perm`: \
+        octal or symbolic (`u+x`), exact or with `-` for all bits (`-o+w`) and `/` for any of them. \
+        `suid()`, `sgid()` and `sticky()` check special bits.\n\
+        `glob(*.rs)` and `re(\"^main\\.rs$\")` match names of files, `path_glob(src/**/*.rs)` and \
+        `path_re(...)` match whole paths. In regular expressions `(?-u:\\xff)` matches a non-UTF-8 byte.\n\
+        Conditions are combined with `&&`, `||` and parentheses, e.g. `*.rs && (type=file || depth=1)`, \
+        and negated with `!` or `not(...)`: `projects/ && !target/`.\n\
+        `{name}` is replaced by the query of the macro `name`, see `macros add`. Arguments of macros \
+        with parameters follow the name, `{big 500M data}`, or are passed like to functions: \
+        `big(500M, data)` or `big(size=500M, dir=data)`.\n\
+        Values with spaces or special characters must be quoted, `\\\"` and `\\\\` stand for the quote and the backslash")
+    .arg(Arg::with_name("explain")
+        .long("explain")
+        .conflicts_with("as-of")
+        .help("Print the parsed query, its SQL and query plan instead of searching"))
+    .arg(Arg::with_name("mount")
+        .long("mount")
+        .short("m")
+        .takes_value(true)
+        .help("Print paths as if every volume is mounted here instead of its current mount point"))
+    .arg(Arg::with_name("volume")
+        .long("volume")
+        .short("v")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Search only in volumes mounted at this path"))
+    .arg(Arg::with_name("group")
+        .long("group")
+        .short("g")
+        .help("Group found files by volume"))
+    .arg(Arg::with_name("long")
+        .long("long")
+        .short("l")
+        .help("Print mode, owner, group, size and modification time of found files"))
+    .arg(Arg::with_name("names")
+        .long("names")
+        .short("n")
+        .requires("long")
+        .help("Print names of owners and groups instead of numeric IDs"))
+    .arg(Arg::with_name("passwd-file")
+        .long("passwd-file")
+        .takes_value(true)
+        .help("Users of `user=...` and --names. Default is `/etc/passwd`"))
+    .arg(Arg::with_name("group-file")
+        .long("group-file")
+        .takes_value(true)
+        .help("Groups of `group=...` and --names. Default is `/etc/group`"))
+    .arg(Arg::with_name("timezone")
+        .long("timezone")
+        .takes_value(true)
+        .help("Timezone of dates in the query and --as-of: `local` (default), `UTC` or offset like `+03:00`"))
+    .arg(Arg::with_name("as-of")
+        .long("as-of")
+        .short("a")
+        .takes_value(true)
+        .help("Search in the recorded history as it was in btrfs snapshot with this UUID or at this date"))
+    .arg(Arg::with_name("query")
+        .multiple(true)
+        .help("Your query"))
+}
+
+fn macros_command() -> App<'static, 'static> {
+    SubCommand::with_name("macros")
+    .about("Manage macroses")
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(SubCommand::with_name("add")
+        .about("Add new macro")
+        .after_help("Parameters are declared after the name, e.g. `big(size: size, dir)`, \
+and are referenced in the query as `$size` and `$dir`: `size>$size && path_glob(\"$dir/**\")`. \
+`$$` stands for the dollar sign. Types of parameters are `text` (the default), `number`, `size` and `time`, \
+arguments are checked against them.\n\
+Precompiled macroses are working faster, but they increase the size of the database. \
+Their matching files are updated together with the index, so they can not use ages or `today`. \
+Macros with parameters are never precompiled")
+        .arg(Arg::with_name("precompile")
+            .long("precompile")
+            .short("c")
+            .help("Precompile this macro"))
+        .arg(Arg::with_name("name")
+            .required(true)
+            .help("Name of this macro with optional parameters, it is referenced in queries as `{name}`"))
+        .arg(Arg::with_name("query")
+            .required(true)
+            .multiple(true)
+            .help("Query. See `query` subcommand for help")))
+    .subcommand(SubCommand::with_name("remove")
+        .about("Remove macro")
+        .arg(Arg::with_name("name")
+            .required(true)
+            .help("Name of the macro")))
+    .subcommand(SubCommand::with_name("list")
+        .about("List all macroses"))
+}
+
+fn subvolume_command() -> App<'static, 'static> {
+    SubCommand::with_name("subvolume")
+    .about("Manage indexed subvolumes")
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(SubCommand::with_name("add")
+        .about("Add new subvolume")
+        .after_help("Btrfs subvolumes are updated from `btrfs send` stream, other directories are walked")
+        .arg(Arg::with_name("exclude")
+            .long("exclude")
+            .short("e")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Gitignore-style pattern of paths to skip while walking"))
+        .arg(Arg::with_name("ignore-file")
+            .long("ignore-file")
+            .short("i")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Name of per-directory files with patterns to skip, e.g. `.gitignore`"))
+        .arg(Arg::with_name("one-file-system")
+            .long("one-file-system")
+            .short("x")
+            .help("Do not descend into other filesystems"))
+        .arg(Arg::with_name("max-depth")
+            .long("max-depth")
+            .takes_value(true)
+            .help("Do not descend deeper than this"))
+        .arg(Arg::with_name("history")
+            .long("history")
+            .help("Keep previous versions of files, see `query --as-of`"))
+        .arg(Arg::with_name("path")
+            .required(true)
+            .help("Path to subvolume")))
+    .subcommand(SubCommand::with_name("remove")
+        .about("Remove subvolume and all its indexed files")
+        .arg(Arg::with_name("path")
+            .required(true)
+            .help("Path or UUID of subvolume")))
+    .subcommand(SubCommand::with_name("list")
+        .about("List all subvolumes"))
+}
+
+#[allow(clippy::match_same_arms)]
+fn run(matches: &ArgMatches) {
     match matches.subcommand() {
-        ("initialize", Some(sub)) => fallback(sub),
+        ("initialize", Some(sub)) => initialize(sub),
         ("update", Some(sub)) => update(sub),
-        ("query", Some(sub)) => fallback(sub),
+        ("watch", Some(sub)) => watch(sub),
+        ("query", Some(sub)) => query(sub),
         ("macros", Some(sub)) => match sub.subcommand() {
-            ("add", Some(sub)) => fallback(sub),
-            ("remove", Some(sub)) => fallback(sub),
-            ("list", Some(sub)) => fallback(sub),
+            ("add", Some(sub)) => macros_add(sub),
+            ("remove", Some(sub)) => macros_remove(sub),
+            ("list", Some(sub)) => macros_list(sub),
             _ => unreachable!(),
         },
         ("subvolume", Some(sub)) => match sub.subcommand() {
-            ("add", Some(sub)) => fallback(sub),
-            ("remove", Some(sub)) => fallback(sub),
-            ("list", Some(sub)) => fallback(sub),
+            ("add", Some(sub)) => subvolume_add(sub),
+            ("remove", Some(sub)) => subvolume_remove(sub),
+            ("list", Some(sub)) => subvolume_list(sub),
             _ => unreachable!(),
         },
         _ => unreachable!(),
     }
 }
+
+fn main() {
+    let matches = App::new("File search")
+        .version(crate_version!())
+        .about("Find any file by name in your btrfs subvolumes")
+        .arg(
+            Arg::with_name("database")
+                .short("d")
+                .long("db")
+                .takes_value(true)
+                .global(true)
+                .help("Path to database. Default is `$XDG_DATA_HOME/file_search/index.sqlite`"),
+        )
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .global_settings(&[
+            AppSettings::ColoredHelp,
+            AppSettings::VersionlessSubcommands,
+        ])
+        .subcommand(
+            SubCommand::with_name("initialize")
+                .about("Initializes empty database")
+                .after_help(
+                    "Database is created at `--db` or at `$XDG_DATA_HOME/file_search/index.sqlite`",
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .short("f")
+                        .help("Overwrite existing database"),
+                ),
+        )
+        .subcommand(update_command())
+        .subcommand(watch_command())
+        .subcommand(query_command())
+        .subcommand(macros_command())
+        .subcommand(subvolume_command())
+        .get_matches();
+
+    run(&matches);
+}
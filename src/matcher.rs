@@ -0,0 +1,370 @@
+use crate::compiler::{resolve, Column, Error, Options, Predicate};
+use crate::literal::PermMatch;
+use crate::model::{depth, FileInfo};
+use crate::query::{Op, Query};
+
+/// Query checked against files in memory, without the database. It has the same semantics
+/// as the SQL condition of `compiler::compile`, precompiled macros are evaluated from their queries
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    predicate: Predicate,
+}
+
+impl Matcher {
+    pub fn new(query: &Query, options: &Options) -> Result<Self, Error> {
+        let (predicate, _) = resolve(query, options)?;
+        Ok(Self { predicate })
+    }
+
+    /// Whether `info` matches, its `filename` is the path relative to the volume
+    pub fn is_match(&self, info: &FileInfo) -> bool {
+        let path = info.filename.to_bytes();
+        evaluate(&self.predicate, &path, info)
+    }
+}
+
+/// Value of `column` as it is compared in SQL
+#[allow(clippy::cast_possible_wrap)]
+fn column_value(column: Column, path: &[u8], info: &FileInfo) -> i64 {
+    match column {
+        Column::Mode => info.permissions as i64 & 0o7777,
+        Column::Uid => info.user_id as i64,
+        Column::Gid => info.group_id as i64,
+        Column::Type => i64::from(info.filetype.to_num()),
+        Column::Length => info.length as i64,
+        Column::Atime => info.accessed.timestamp_nanos(),
+        Column::Mtime => info.modified.timestamp_nanos(),
+        Column::Ctime => info.created.timestamp_nanos(),
+        Column::Depth => depth(path),
+    }
+}
+
+const fn compare(left: i64, op: Op, right: i64) -> bool {
+    match op {
+        Op::Eq => left == right,
+        Op::Lt => left < right,
+        Op::Le => left <= right,
+        Op::Gt => left > right,
+        Op::Ge => left >= right,
+    }
+}
+
+fn contains(path: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || path.windows(needle.len()).any(|window| window == needle)
+}
+
+fn evaluate(predicate: &Predicate, path: &[u8], info: &FileInfo) -> bool {
+    match predicate {
+        Predicate::Or(items) => items.iter().any(|item| evaluate(item, path, info)),
+        Predicate::And(items) => items.iter().all(|item| evaluate(item, path, info)),
+        Predicate::Not(inner) => !evaluate(inner, path, info),
+        Predicate::Contains(needle) => contains(path, needle),
+        Predicate::EndsWith(suffix) => path.ends_with(suffix),
+        Predicate::Path(expected) => path == expected.as_slice(),
+        Predicate::Name(name) => {
+            path == name.as_slice()
+                || path.ends_with(name) && path[..path.len() - name.len()].ends_with(b"/")
+        }
+        Predicate::Compare { column, op, value } => {
+            compare(column_value(*column, path, info), *op, *value)
+        }
+        // Time of the file is compared with the interval the same way as in SQL
+        Predicate::Time {
+            column,
+            op,
+            start,
+            end,
+        } => {
+            let time = column_value(*column, path, info);
+            match op {
+                Op::Eq => *start <= time && time < *end,
+                Op::Lt => time < *start,
+                Op::Le => time < *end,
+                Op::Gt => time >= *end,
+                Op::Ge => time >= *start,
+            }
+        }
+        Predicate::Perm { kind, mode } => {
+            let actual = column_value(Column::Mode, path, info);
+            let mode = i64::from(*mode);
+            match kind {
+                PermMatch::Exact => actual & 0o7777 == mode,
+                PermMatch::All => actual & mode == mode,
+                PermMatch::Any => actual & mode != 0,
+            }
+        }
+        Predicate::ModeBit(bit) => column_value(Column::Mode, path, info) & i64::from(*bit) != 0,
+        Predicate::Pattern { pattern, .. } => pattern.is_match(path),
+        Predicate::Macro { body, .. } => evaluate(body, path, info),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Matcher;
+    use crate::accounts::Accounts;
+    use crate::compiler::{compile, parse_params, Macro, Options, Target};
+    use crate::database::Database;
+    use crate::literal::Timezone;
+    use crate::mixed::MixedString;
+    use crate::model::{FileInfo, FileType, SubvolumeInfo, SubvolumeSource};
+    use crate::query::parse;
+    use chrono::{NaiveDate, NaiveDateTime};
+    use std::collections::HashMap;
+
+    /// Deterministic xorshift generator, so failures are reproducible
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.next() as usize % items.len()]
+        }
+    }
+
+    /// UTC dates, now is 2024-01-10 00:00:00
+    fn options() -> Options {
+        Options {
+            timezone: Timezone::parse("UTC").unwrap(),
+            now: NaiveDate::from_ymd(2024, 1, 10).and_hms(0, 0, 0),
+            accounts: Accounts::parse(
+                "alice:x:1000:100::/:/bin/sh\nbob:x:1001:100::/:/bin/sh\n",
+                "users:x:100:\nwheel:x:10:alice\n",
+            ),
+            ..Options::new(Target::Files)
+        }
+    }
+
+    /// Files with paths of different depth, Unicode and invalid bytes, and random attributes
+    #[allow(clippy::cast_possible_wrap)]
+    fn generate(random: &mut Random, count: usize) -> Vec<FileInfo> {
+        let components: &[&[u8]] = &[
+            b"src",
+            b"main.rs",
+            b"lib.rs",
+            b"README.md",
+            b"target",
+            b"Main.RS",
+            "привет".as_bytes(),
+            "cafe\u{301}.txt".as_bytes(),
+            b"\xff\xfe.rs",
+            b"a\xe4\xbd.c",
+            b"say \"hi\"",
+            b"x",
+        ];
+        let modes = [0o644, 0o755, 0o4755, 0o2750, 0o1777, 0o600, 0o640];
+        let types = [
+            FileType::File,
+            FileType::File,
+            FileType::Directory,
+            FileType::Symlink,
+            FileType::Fifo,
+        ];
+        let now = NaiveDate::from_ymd(2024, 1, 10)
+            .and_hms(0, 0, 0)
+            .timestamp();
+        let mut files: HashMap<Vec<u8>, FileInfo> = HashMap::new();
+        while files.len() < count {
+            let depth = 1 + random.next() % 4;
+            let path: Vec<&[u8]> = (0..depth).map(|_| *random.pick(components)).collect();
+            let path = path.join(&b'/');
+            let time = |random: &mut Random| {
+                // Within a month before now, sometimes in the future
+                let offset = (random.next() % (32 * 86_400)) as i64 - 86_400;
+                NaiveDateTime::from_timestamp(now - offset, (random.next() % 1000) as u32)
+            };
+            let filetype = *random.pick(&types);
+            // Type bits are stored as `find::file_info` does
+            let type_bits = match filetype {
+                FileType::Directory => libc::S_IFDIR,
+                FileType::Symlink => libc::S_IFLNK,
+                FileType::Fifo => libc::S_IFIFO,
+                _ => libc::S_IFREG,
+            };
+            let info = FileInfo {
+                filename: MixedString::from_bytes(&path),
+                permissions: u64::from(type_bits) | *random.pick(&modes),
+                modified: time(random),
+                accessed: time(random),
+                created: time(random),
+                length: *random.pick(&[0, 1, 999, 1000, 1001, 1_500_000, 2_000_000_000]),
+                user_id: *random.pick(&[0, 1000, 1001]),
+                group_id: *random.pick(&[0, 10, 100]),
+                filetype,
+            };
+            files.insert(path, info);
+        }
+        files.into_values().collect()
+    }
+
+    fn load(db: &mut Database, files: &[FileInfo]) {
+        let subvolume = SubvolumeInfo {
+            source: SubvolumeSource::Find {
+                path: MixedString::from_str("/volume"),
+            },
+            overwrite: true,
+            files: files
+                .iter()
+                .map(|info| (info.filename.clone(), Some(info.clone())))
+                .collect(),
+        };
+        db.insert_data(vec![subvolume], &[]).unwrap();
+    }
+
+    /// Adds macros to the database and to `options`, `code` is precompiled
+    fn add_macros(db: &mut Database, options: &mut Options) {
+        for &(name, params, text, precompile) in &[
+            ("rust", "", "*.rs && type=file", false),
+            ("code", "", "{rust} || glob(*.c)", true),
+            ("recent", "", "mtime>2024-01-05", false),
+            (
+                "over",
+                "size: size, dir",
+                r#"size>$size && path_glob("$dir/**")"#,
+                false,
+            ),
+        ] {
+            let query = parse(text).unwrap();
+            let compiled = if precompile {
+                let condition = compile(&query, options).unwrap();
+                Some(db.add_macro(name, params, text, Some(&condition)).unwrap())
+            } else {
+                None
+            };
+            let definition = Macro {
+                query,
+                compiled,
+                params: parse_params(params).unwrap(),
+            };
+            options.macros.insert(name.to_string(), definition);
+        }
+    }
+
+    const QUERIES: &[&str] = &[
+        r#""""#,
+        "main",
+        "rs",
+        "src/main",
+        "привет/",
+        r#""say \"hi\"""#,
+        r#""\u{ff}""#,
+        r#""\u{e4}\u{bd}.c""#,
+        "*.rs",
+        "*.RS",
+        "*/x",
+        "*\u{301}.txt",
+        r#""*\u{fe}.rs""#,
+        "*",
+        "name=main.rs",
+        "name=x",
+        "path=x",
+        "path=src/x",
+        "type=dir",
+        "type=file && !*.rs",
+        "size>1000",
+        "size<=1k",
+        "size=1.5M",
+        "size>=2G || length=0",
+        "depth=1",
+        "depth>2 && depth<=3",
+        "mode=644",
+        "perm=755",
+        "perm=-g+s",
+        "perm=/o+w",
+        r#"perm="u=rw,go=""#,
+        "suid() || sticky()",
+        "!sgid()",
+        "user=alice",
+        "user=1001 || group=wheel",
+        "uid=0 && gid=100",
+        "mtime=2024-01-05",
+        r#"mtime>"2024-01-05T12:00""#,
+        "atime<3d",
+        "ctime=1d || ctime>=7d",
+        "mtime>=today",
+        "atime=yesterday",
+        "mtime<1704800000",
+        "re(^main)",
+        r#"re("\.rs$")"#,
+        r#"path_re("^src/.+/x$")"#,
+        r#"re("(?i)^readme")"#,
+        "glob(*.rs)",
+        r#"path_glob("src/**")"#,
+        "path_glob(*/*.c)",
+        r#"glob("{main,lib}.rs")"#,
+        "{rust}",
+        "{code} && !{recent}",
+        "!({code} || type=dir)",
+        "not(main || lib) && src",
+        "!!target",
+        "{over 1k src}",
+        "over(size=1M, dir=привет) || !over(0, src/*)",
+        "(main || lib) && (size>1000 || !rs) && !path_glob(**/x)",
+    ];
+
+    #[test]
+    fn same_as_sql() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        let files = generate(&mut random, 400);
+        let mut db = Database::connect(":memory:").unwrap();
+        load(&mut db, &files);
+        let mut options = options();
+        add_macros(&mut db, &mut options);
+
+        for text in QUERIES {
+            let query = parse(text).unwrap();
+            let condition = compile(&query, &options).unwrap();
+            let mut expected: Vec<Vec<u8>> = db
+                .search(&condition)
+                .unwrap()
+                .into_iter()
+                .map(|(_, info)| info.filename.to_bytes())
+                .collect();
+            let matcher = Matcher::new(&query, &options).unwrap();
+            let mut actual: Vec<Vec<u8>> = files
+                .iter()
+                .filter(|info| matcher.is_match(info))
+                .map(|info| info.filename.to_bytes())
+                .collect();
+            expected.sort();
+            actual.sort();
+            assert_eq!(actual, expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn permissions() {
+        let options = options();
+        let file = generate(&mut Random(1), 1)
+            .pop()
+            .map(|info| FileInfo {
+                permissions: u64::from(libc::S_IFREG) | 0o644,
+                filetype: FileType::File,
+                ..info
+            })
+            .unwrap();
+        for text in &["mode=644", "perm=644", "perm=-u+w", "!suid()"] {
+            let matcher = Matcher::new(&parse(text).unwrap(), &options).unwrap();
+            assert!(matcher.is_match(&file), "{}", text);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let options = options();
+        for text in &["color=1", "{missing}", "re(\"(\")", "mode>1", "user=nobody"] {
+            let query = parse(text).unwrap();
+            assert_eq!(
+                Matcher::new(&query, &options).unwrap_err(),
+                compile(&query, &options).unwrap_err()
+            );
+        }
+    }
+}
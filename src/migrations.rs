@@ -0,0 +1,340 @@
+use rusqlite::{named_params, Connection, Error};
+
+//noinspection SqlNoDataSourceInspection
+const INITIAL_SQL: &str = r#"
+
+CREATE TABLE "files" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "fts_id" INTEGER NOT NULL UNIQUE,
+    "volume" INTEGER NOT NULL,
+    "path" BLOB NOT NULL,
+    "depth" INTEGER NOT NULL,
+
+    "mode" INTEGER NOT NULL,
+
+    "uid" INTEGER NOT NULL,
+    "gid" INTEGER NOT NULL,
+    
+    "atime" INTEGER NOT NULL,
+    "mtime" INTEGER NOT NULL,
+    "ctime" INTEGER NOT NULL,
+    
+    "type" INTEGER NOT NULL,
+    "length" INTEGER NOT NULL,
+
+    FOREIGN KEY ("volume") REFERENCES "volumes"("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_files_ftsid" ON "files" ("fts_id");
+CREATE UNIQUE INDEX "idx_files_path" ON "files" ("volume", "path");
+CREATE INDEX "idx_files_mode" ON "files" ("mode");
+CREATE INDEX "idx_files_uid" ON "files" ("uid");
+CREATE INDEX "idx_files_gid" ON "files" ("gid");
+CREATE INDEX "idx_files_type" ON "files" ("type");
+CREATE INDEX "idx_files_length" ON "files" ("length");
+CREATE INDEX "idx_files_atime" ON "files" ("atime");
+CREATE INDEX "idx_files_mtime" ON "files" ("mtime");
+CREATE INDEX "idx_files_ctime" ON "files" ("ctime");
+
+CREATE TABLE "compiled" (
+	"macro" INTEGER NOT NULL,
+	"file" INTEGER NOT NULL,
+	PRIMARY KEY ("macro", "file"),
+	FOREIGN KEY ("macro") REFERENCES "macroses"("id") ON DELETE CASCADE,
+	FOREIGN KEY ("file") REFERENCES "files"("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_compiled_macro" ON "compiled" ("macro");
+
+CREATE TABLE "macroses" (
+	"id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	"query"	TEXT NOT NULL
+);
+
+CREATE INDEX "idx_macroses_query" ON "macroses" ("query");
+
+CREATE TABLE "volumes" (
+	"id"	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	"type"	INTEGER NOT NULL,
+	"data"	TEXT,
+	"mount"	BLOB,
+	"settings"	TEXT,
+	"updated"	INTEGER
+);
+
+CREATE TABLE "settings" (
+    "version" INTEGER NOT NULL,
+    "cache_size" INTEGER NOT NULL
+);
+
+CREATE TABLE "filters" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT ,
+    "query" TEXT NOT NULL
+);
+CREATE INDEX "idx_filters_query" ON "filters" ("query");
+
+-- No any separators
+CREATE VIRTUAL TABLE files_fts USING fts5(
+	path,
+	rev_path,
+    tokenize = "unicode61 remove_diacritics 0 categories 'L* M* N* P* S* Z* C*'"
+);
+
+INSERT INTO "settings" VALUES (
+    0,
+    10000
+);
+"#;
+
+/// Deleted files are looked up in "compiled" by file, so cascade does not scan the whole table
+const COMPILED_FILE_INDEX_SQL: &str = r#"
+CREATE INDEX "idx_compiled_file" ON "compiled" ("file");
+"#;
+
+/// Every path is split into trigrams, so any substring of at least three characters is found
+/// without scanning the whole table. Its rows share rowid with "`files_fts`"
+const TRIGRAM_SQL: &str = r#"
+CREATE VIRTUAL TABLE files_trigram USING fts5(
+    path,
+    tokenize = "trigram case_sensitive 1"
+);
+
+INSERT INTO "files_trigram" ("rowid", "path")
+SELECT "rowid", "path"
+FROM "files_fts";
+"#;
+
+/// Optional history of volumes. Every update of such volume is a snapshot with increasing
+/// "transid": ctransid of btrfs snapshot or number of the update. Every metadata version
+/// of a file existed in snapshots from "since" up to, but not including, "until"
+const HISTORY_SQL: &str = r#"
+ALTER TABLE "volumes" ADD COLUMN "history" INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE "snapshots" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "volume" INTEGER NOT NULL,
+    "uuid" TEXT,
+    "transid" INTEGER NOT NULL,
+    "created" INTEGER NOT NULL,
+
+    FOREIGN KEY ("volume") REFERENCES "volumes"("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_snapshots_volume" ON "snapshots" ("volume", "transid");
+CREATE INDEX "idx_snapshots_uuid" ON "snapshots" ("uuid");
+
+CREATE TABLE "history" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "volume" INTEGER NOT NULL,
+    "path" BLOB NOT NULL,
+
+    "mode" INTEGER NOT NULL,
+    "uid" INTEGER NOT NULL,
+    "gid" INTEGER NOT NULL,
+    "atime" INTEGER NOT NULL,
+    "mtime" INTEGER NOT NULL,
+    "ctime" INTEGER NOT NULL,
+    "type" INTEGER NOT NULL,
+    "length" INTEGER NOT NULL,
+
+    "since" INTEGER NOT NULL,
+    "until" INTEGER,
+
+    FOREIGN KEY ("volume") REFERENCES "volumes"("id") ON DELETE CASCADE
+);
+
+CREATE INDEX "idx_history_path" ON "history" ("volume", "path");
+"#;
+
+/// Macros are referenced by name as `{name}`. Precompiled ones have their matching files
+/// in "compiled"
+const MACRO_NAMES_SQL: &str = r#"
+ALTER TABLE "macroses" ADD COLUMN "name" TEXT;
+ALTER TABLE "macroses" ADD COLUMN "precompiled" INTEGER NOT NULL DEFAULT 0;
+
+UPDATE "macroses"
+SET "name" = 'macro' || "id",
+    "precompiled" = EXISTS (SELECT 1 FROM "compiled" WHERE "macro" = "macroses"."id");
+
+CREATE UNIQUE INDEX "idx_macroses_name" ON "macroses" ("name");
+"#;
+
+/// Parameters of macros, e.g. `size: size, dir`. Macros without them have empty "params"
+const MACRO_PARAMS_SQL: &str = r#"
+ALTER TABLE "macroses" ADD COLUMN "params" TEXT NOT NULL DEFAULT '';
+"#;
+
+/// Permissions are compared without the file type bits of "mode", see `Column::Mode`.
+/// Index of the whole column cannot be used for that
+const PERMISSIONS_INDEX_SQL: &str = r#"
+DROP INDEX "idx_files_mode";
+CREATE INDEX "idx_files_perm" ON "files" ("mode" & 4095);
+"#;
+
+/// Every schema change, in order of application. Database with `settings.version = N`
+/// has first `N` migrations applied. Released migrations must never be changed,
+/// new ones are appended to the end
+const MIGRATIONS: &[&str] = &[
+    INITIAL_SQL,
+    COMPILED_FILE_INDEX_SQL,
+    TRIGRAM_SQL,
+    HISTORY_SQL,
+    MACRO_NAMES_SQL,
+    MACRO_PARAMS_SQL,
+    PERMISSIONS_INDEX_SQL,
+];
+
+/// Version of the schema created by all known migrations
+#[allow(clippy::cast_possible_truncation)]
+pub const LATEST_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Version of the schema, empty database has version 0
+pub fn version(connection: &Connection) -> Result<u32, Error> {
+    const HAS_SETTINGS_SQL: &str = r#"
+        SELECT COUNT(*)
+        FROM "sqlite_master"
+        WHERE "type" = 'table' AND "name" = 'settings'
+    "#;
+    const SELECT_VERSION_SQL: &str = r#"
+        SELECT "version"
+        FROM "settings"
+    "#;
+
+    let has_settings: i64 =
+        connection.query_row(HAS_SETTINGS_SQL, rusqlite::NO_PARAMS, |row| row.get(0))?;
+    if has_settings == 0 {
+        return Ok(0);
+    }
+    connection.query_row(SELECT_VERSION_SQL, rusqlite::NO_PARAMS, |row| row.get(0))
+}
+
+/// Applies migrations until `target` version is reached. Every migration is applied in
+/// its own transaction, so interrupted upgrade can be continued later
+fn migrate_to(connection: &mut Connection, target: u32) -> Result<u32, Error> {
+    const UPDATE_VERSION_SQL: &str = r#"
+        UPDATE "settings"
+        SET "version" = :version
+    "#;
+
+    let current = version(connection)?;
+    if current > LATEST_VERSION {
+        return Err(Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISMATCH),
+            Some(format!(
+                "Database schema version {current} is newer than supported {LATEST_VERSION}"
+            )),
+        ));
+    }
+    for (version, migration) in (current..target).zip(&MIGRATIONS[current as usize..]) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.execute_named(
+            UPDATE_VERSION_SQL,
+            named_params! {
+                ":version": version + 1
+            },
+        )?;
+        transaction.commit()?;
+    }
+    Ok(current.max(target))
+}
+
+/// Upgrades schema to the latest version. Returns resulting version
+pub fn migrate(connection: &mut Connection) -> Result<u32, Error> {
+    migrate_to(connection, LATEST_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, migrate_to, version, LATEST_VERSION, MIGRATIONS};
+    use rusqlite::{Connection, NO_PARAMS};
+
+    /// Definitions of all tables and indices
+    fn schema(connection: &Connection) -> Vec<String> {
+        let mut select = connection
+            .prepare(r#"SELECT "sql" FROM "sqlite_master" WHERE "sql" IS NOT NULL ORDER BY "name""#)
+            .unwrap();
+        let rows = select.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn empty() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        assert_eq!(version(&connection).unwrap(), 0);
+        assert_eq!(migrate(&mut connection).unwrap(), LATEST_VERSION);
+        assert_eq!(version(&connection).unwrap(), LATEST_VERSION);
+        assert!(!schema(&connection).is_empty());
+    }
+
+    #[test]
+    fn idempotent() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        let before = schema(&connection);
+        assert_eq!(migrate(&mut connection).unwrap(), LATEST_VERSION);
+        assert_eq!(schema(&connection), before);
+    }
+
+    #[test]
+    fn step_by_step() {
+        let mut all = Connection::open_in_memory().unwrap();
+        migrate(&mut all).unwrap();
+
+        // Every intermediate version must be upgradable to the same schema
+        for start in 0..LATEST_VERSION {
+            let mut connection = Connection::open_in_memory().unwrap();
+            migrate_to(&mut connection, start).unwrap();
+            assert_eq!(version(&connection).unwrap(), start);
+            migrate(&mut connection).unwrap();
+            assert_eq!(schema(&connection), schema(&all), "from {start}");
+        }
+        assert_eq!(MIGRATIONS.len(), LATEST_VERSION as usize);
+    }
+
+    #[test]
+    fn newer() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        connection
+            .execute(
+                r#"UPDATE "settings" SET "version" = "version" + 1"#,
+                NO_PARAMS,
+            )
+            .unwrap();
+        assert!(migrate(&mut connection).is_err());
+    }
+
+    #[test]
+    fn cascade() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection
+            .pragma_update(None, "foreign_keys", &"ON")
+            .unwrap();
+        migrate(&mut connection).unwrap();
+        connection
+            .execute_batch(
+                r#"
+                INSERT INTO "volumes" ("id", "type", "data") VALUES (1, 1, '/a'), (2, 1, '/b');
+                INSERT INTO "files" (
+                    "fts_id", "volume", "path", "depth", "mode", "uid", "gid",
+                    "atime", "mtime", "ctime", "type", "length"
+                ) VALUES
+                    (1, 1, X'', 0, 0, 0, 0, 0, 0, 0, 1, 0),
+                    (2, 2, X'', 0, 0, 0, 0, 0, 0, 0, 1, 0);
+                INSERT INTO "macroses" ("id", "query") VALUES (1, 'a');
+                INSERT INTO "compiled" ("macro", "file") VALUES (1, 1), (1, 2);
+                DELETE FROM "volumes" WHERE "id" = 1;
+                "#,
+            )
+            .unwrap();
+        let count = |table: &str| -> i64 {
+            let sql = format!(r#"SELECT COUNT(*) FROM "{table}""#);
+            connection
+                .query_row(&sql, NO_PARAMS, |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count("files"), 1);
+        assert_eq!(count("compiled"), 1);
+    }
+}
@@ -96,6 +96,15 @@ impl MixedString {
         res
     }
 
+    /// Valid UTF-8 parts of the string. Each of them is kept as is by `to_string`
+    /// of any other string containing this one
+    pub fn strings(&self) -> impl Iterator<Item = &str> {
+        self.data.iter().filter_map(|data| match data {
+            Mixed::String(s) => Some(s.as_str()),
+            _ => None,
+        })
+    }
+
     pub fn reverse(&mut self) {
         for data in &mut self.data {
             match data {
@@ -261,6 +270,18 @@ mod tests {
         (s, input, r)
     });
 
+    #[test]
+    fn strings() {
+        let mut input: Vec<u8> = b"Hello ".to_vec();
+        input.extend_from_slice(INVALID);
+        input.extend_from_slice(b"world");
+        input.push(TRUNCATED);
+
+        let mixed = MixedString::from_bytes(&input);
+        let strings: Vec<&str> = mixed.strings().collect();
+        assert_eq!(strings, vec!["Hello ", "world"]);
+    }
+
     #[test]
     fn reverse_emoji() {
         let s = "123 \u{1F937}";
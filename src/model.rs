@@ -1,6 +1,7 @@
 use crate::mixed::MixedString;
 use chrono::NaiveDateTime;
 use std::collections::HashMap;
+use std::fmt::Write;
 use std::fs;
 use std::os::unix::fs::FileTypeExt;
 
@@ -22,6 +23,33 @@ impl FileType {
     pub const fn to_num(self) -> u8 {
         self as u8
     }
+
+    pub const fn from_num(num: u8) -> Self {
+        match num {
+            0 => Self::File,
+            1 => Self::Directory,
+            2 => Self::Symlink,
+            3 => Self::BlockDevice,
+            4 => Self::CharDevice,
+            5 => Self::Fifo,
+            6 => Self::Socket,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Parses type names of queries, single letters are the same as in `find -type`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "file" | "f" => Some(Self::File),
+            "dir" | "directory" | "d" => Some(Self::Directory),
+            "symlink" | "link" | "l" => Some(Self::Symlink),
+            "block" | "b" => Some(Self::BlockDevice),
+            "char" | "c" => Some(Self::CharDevice),
+            "fifo" | "p" => Some(Self::Fifo),
+            "socket" | "s" => Some(Self::Socket),
+            _ => None,
+        }
+    }
 }
 
 impl From<fs::FileType> for FileType {
@@ -46,7 +74,7 @@ impl From<fs::FileType> for FileType {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct FileInfo {
     pub filename: MixedString,
     // https://doc.rust-lang.org/std/os/unix/fs/trait.PermissionsExt.html#tymethod.mode
@@ -60,10 +88,83 @@ pub struct FileInfo {
     pub filetype: FileType,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VolumeType {
+    Btrfs = 0,
+    Find = 1,
+}
+
+impl VolumeType {
+    pub const fn to_num(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn from_num(num: u8) -> Self {
+        match num {
+            0 => Self::Btrfs,
+            _ => Self::Find,
+        }
+    }
+}
+
+/// Formats UUID from send stream as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`
+pub fn format_uuid(uuid: u128) -> String {
+    let mut res = String::with_capacity(36);
+    for (i, byte) in uuid.to_le_bytes().iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            res.push('-');
+        }
+        let _ = write!(res, "{byte:02x}");
+    }
+    res
+}
+
+/// Parses UUID formatted by `format_uuid`
+pub fn parse_uuid(s: &str) -> Option<u128> {
+    let dashes = [8, 13, 18, 23];
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b'-').collect();
+    if hex.len() != 32 || !dashes.iter().all(|&i| s.as_bytes().get(i) == Some(&b'-')) {
+        return None;
+    }
+    let mut bytes = [0; 16];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(u128::from_le_bytes(bytes))
+}
+
+/// Depth of path relative to the volume root. Root itself has zero depth
+#[allow(clippy::cast_possible_wrap)]
+pub fn depth(path: &[u8]) -> i64 {
+    if path.is_empty() {
+        0
+    } else {
+        path.split(|&b| b == b'/').count() as i64
+    }
+}
+
 #[derive(Debug)]
 pub enum SubvolumeSource {
-    Btrfs { uuid: u128 },
-    Find { path: MixedString },
+    /// `parent` is set for incremental streams, which are applied to already indexed parent snapshot.
+    /// `ctransid` is the transaction the snapshot was taken at, if it is known
+    Btrfs {
+        uuid: u128,
+        parent: Option<u128>,
+        ctransid: Option<u64>,
+    },
+    Find {
+        path: MixedString,
+    },
+}
+
+impl SubvolumeSource {
+    pub const fn volume_type(&self) -> VolumeType {
+        match self {
+            Self::Btrfs { .. } => VolumeType::Btrfs,
+            Self::Find { .. } => VolumeType::Find,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -72,3 +173,29 @@ pub struct SubvolumeInfo {
     pub overwrite: bool,
     pub files: HashMap<MixedString, Option<FileInfo>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_uuid, parse_uuid};
+
+    #[test]
+    fn uuid() {
+        let bytes = [
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+            0xcd, 0xef,
+        ];
+        assert_eq!(
+            format_uuid(u128::from_le_bytes(bytes)),
+            "12345678-9abc-def0-0123-456789abcdef"
+        );
+    }
+
+    #[test]
+    fn uuid_roundtrip() {
+        let uuid = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210;
+        assert_eq!(parse_uuid(&format_uuid(uuid)), Some(uuid));
+        assert_eq!(parse_uuid("2020-01-01"), None);
+        assert_eq!(parse_uuid("12345678-9abc-def0-0123-456789abcdeg"), None);
+        assert_eq!(parse_uuid("123456789-abc-def0-0123-456789abcdef"), None);
+    }
+}
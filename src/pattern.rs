@@ -0,0 +1,245 @@
+use globset::GlobBuilder;
+use regex::bytes::Regex;
+use regex_syntax::hir::{Hir, HirKind, Literal, RepetitionKind, RepetitionRange};
+use regex_syntax::ParserBuilder;
+
+/// Syntax of a pattern
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    /// Regular expression, which matches any part of the text unless it is anchored
+    Regex,
+    /// Shell glob, which matches the whole text. In paths `*` does not match `/`, but `**` does
+    Glob,
+}
+
+/// Part of the path that is matched
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Last component of the path
+    Name,
+    /// Whole path relative to the volume
+    Path,
+}
+
+/// SQL functions `name(pattern, path)` registered by `register`
+const FUNCTIONS: &[(&str, Syntax, Scope)] = &[
+    ("regexp", Syntax::Regex, Scope::Path),
+    ("regexp_name", Syntax::Regex, Scope::Name),
+    ("glob_path", Syntax::Glob, Scope::Path),
+    ("glob_name", Syntax::Glob, Scope::Name),
+];
+
+/// Last component of `path`
+pub fn basename(path: &[u8]) -> &[u8] {
+    path.iter()
+        .rposition(|&b| b == b'/')
+        .map_or(path, |pos| &path[pos + 1..])
+}
+
+/// Name of the SQL function matching `scope` of paths with `syntax`
+pub fn sql_function(syntax: Syntax, scope: Scope) -> &'static str {
+    FUNCTIONS
+        .iter()
+        .find(|&&(_, s, p)| s == syntax && p == scope)
+        .map(|&(name, _, _)| name)
+        .expect("every pattern kind is registered")
+}
+
+/// Pattern compiled to a regular expression over raw bytes, so non-UTF-8 names are matched too
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    regex: Regex,
+    scope: Scope,
+    /// The longest literal that every match contains, if any
+    required: Vec<u8>,
+}
+
+impl Pattern {
+    pub fn new(syntax: Syntax, scope: Scope, text: &str) -> Result<Self, String> {
+        let regex = match syntax {
+            Syntax::Regex => text.to_string(),
+            Syntax::Glob => GlobBuilder::new(text)
+                .literal_separator(scope == Scope::Path)
+                .build()
+                .map_err(|err| err.kind().to_string())?
+                .regex()
+                .to_string(),
+        };
+        let hir = ParserBuilder::new()
+            .allow_invalid_utf8(true)
+            .build()
+            .parse(&regex)
+            .map_err(|err| match err {
+                regex_syntax::Error::Parse(err) => err.kind().to_string(),
+                regex_syntax::Error::Translate(err) => err.kind().to_string(),
+                err => err.to_string(),
+            })?;
+        let required = required_literals(&hir)
+            .into_iter()
+            .max_by_key(Vec::len)
+            .unwrap_or_default();
+        Ok(Self {
+            regex: Regex::new(&regex).map_err(|err| err.to_string())?,
+            scope,
+            required,
+        })
+    }
+
+    pub fn is_match(&self, path: &[u8]) -> bool {
+        match self.scope {
+            Scope::Name => self.regex.is_match(basename(path)),
+            Scope::Path => self.regex.is_match(path),
+        }
+    }
+
+    pub const fn scope(&self) -> Scope {
+        self.scope
+    }
+
+    /// Literal that every matched path contains, it may be looked up in the trigram index
+    pub fn required(&self) -> &[u8] {
+        &self.required
+    }
+}
+
+/// Literal runs that are present in every match of `hir`, in no particular order
+fn required_literals(hir: &Hir) -> Vec<Vec<u8>> {
+    match hir.kind() {
+        HirKind::Literal(literal) => vec![literal_bytes(literal)],
+        HirKind::Group(group) => required_literals(&group.hir),
+        HirKind::Repetition(repetition) => {
+            let required = match &repetition.kind {
+                RepetitionKind::ZeroOrOne | RepetitionKind::ZeroOrMore => false,
+                RepetitionKind::OneOrMore => true,
+                RepetitionKind::Range(
+                    RepetitionRange::Exactly(min)
+                    | RepetitionRange::AtLeast(min)
+                    | RepetitionRange::Bounded(min, _),
+                ) => *min > 0,
+            };
+            if required {
+                required_literals(&repetition.hir)
+            } else {
+                Vec::new()
+            }
+        }
+        HirKind::Concat(items) => {
+            let mut res = Vec::new();
+            let mut run = Vec::new();
+            for item in items {
+                if let HirKind::Literal(literal) = item.kind() {
+                    run.extend(literal_bytes(literal));
+                } else {
+                    res.push(std::mem::take(&mut run));
+                    res.extend(required_literals(item));
+                }
+            }
+            res.push(run);
+            res
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn literal_bytes(literal: &Literal) -> Vec<u8> {
+    match literal {
+        Literal::Unicode(c) => c.to_string().into_bytes(),
+        Literal::Byte(b) => vec![*b],
+    }
+}
+
+/// Registers `regexp`, `regexp_name`, `glob_path` and `glob_name` functions. Patterns are
+/// compiled once per statement, the path is matched as raw bytes.
+/// `regexp` also backs the `REGEXP` operator
+pub fn register(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+    for &(name, syntax, scope) in FUNCTIONS {
+        connection.create_scalar_function(name, 2, true, move |ctx| {
+            let path = ctx
+                .get_raw(1)
+                .as_blob()
+                .map_err(|err| rusqlite::Error::UserFunctionError(err.into()))?;
+            if let Some(pattern) = ctx.get_aux::<Pattern>(0)? {
+                return Ok(pattern.is_match(path));
+            }
+            let pattern = Pattern::new(syntax, scope, &ctx.get::<String>(0)?)
+                .map_err(|err| rusqlite::Error::UserFunctionError(err.into()))?;
+            let is_match = pattern.is_match(path);
+            ctx.set_aux(0, pattern);
+            Ok(is_match)
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{basename, register, Pattern, Scope, Syntax};
+
+    fn matches(syntax: Syntax, scope: Scope, pattern: &str, path: &[u8]) -> bool {
+        Pattern::new(syntax, scope, pattern).unwrap().is_match(path)
+    }
+
+    fn required(syntax: Syntax, pattern: &str) -> String {
+        let pattern = Pattern::new(syntax, Scope::Path, pattern).unwrap();
+        String::from_utf8(pattern.required().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn globs() {
+        let glob = |scope, pattern, path| matches(Syntax::Glob, scope, pattern, path);
+        assert!(glob(Scope::Name, "*.rs", b"src/main.rs"));
+        assert!(!glob(Scope::Name, "*.rs", b"src.rs/main"));
+        assert!(glob(Scope::Name, "ma?n.{rs,c}", b"main.c"));
+        assert!(glob(Scope::Name, "*.txt", b"dir/\xff.txt"));
+        assert!(!glob(Scope::Path, "*.rs", b"src/main.rs"));
+        assert!(glob(Scope::Path, "src/*.rs", b"src/main.rs"));
+        assert!(glob(Scope::Path, "**/*.rs", b"a/b/c.rs"));
+        assert!(!glob(Scope::Path, "src/*.rs", b"src/bin/main.rs"));
+        assert!(glob(Scope::Path, "src/**", b"src/bin/main.rs"));
+        assert!(Pattern::new(Syntax::Glob, Scope::Name, "[a-").is_err());
+    }
+
+    #[test]
+    fn regexes() {
+        let regex = |scope, pattern, path| matches(Syntax::Regex, scope, pattern, path);
+        assert!(regex(Scope::Name, r"^m.*\.rs$", b"src/main.rs"));
+        assert!(!regex(Scope::Name, "^src", b"src/main.rs"));
+        assert!(regex(Scope::Path, "^src/", b"src/main.rs"));
+        assert!(regex(Scope::Path, r"(?-u:\xff)\.txt$", b"dir/\xff.txt"));
+        assert!(regex(Scope::Name, r"^.\.txt$", b"dir/\xc3\xa9.txt"));
+        assert!(Pattern::new(Syntax::Regex, Scope::Name, "(a").is_err());
+    }
+
+    #[test]
+    fn required_literals() {
+        assert_eq!(required(Syntax::Regex, r"^src/.*\.rs$"), "src/");
+        assert_eq!(required(Syntax::Regex, "fo(barx)+baz"), "barx");
+        assert_eq!(required(Syntax::Regex, "(foobar)?x"), "x");
+        assert_eq!(required(Syntax::Regex, "foo|barbaz"), "");
+        assert_eq!(required(Syntax::Regex, "(?i)foobar"), "");
+        assert_eq!(required(Syntax::Glob, "**/target/*.o"), "target/");
+        assert_eq!(required(Syntax::Glob, "*.{rs,c}"), ".");
+        assert_eq!(basename(b"a/b/c"), b"c");
+        assert_eq!(basename(b"c"), b"c");
+    }
+
+    #[test]
+    fn sql_functions() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        register(&connection).unwrap();
+        let is_match = |sql: &str, path: &[u8]| -> bool {
+            connection
+                .query_row(sql, &[&path], |row| row.get(0))
+                .unwrap()
+        };
+        assert!(is_match("SELECT ?1 REGEXP 'b/c$'", b"a/b/c"));
+        assert!(is_match("SELECT regexp_name('^c$', ?1)", b"a/b/c"));
+        assert!(is_match("SELECT glob_path('a/*/c', ?1)", b"a/\xff/c"));
+        assert!(!is_match("SELECT glob_name('a*', ?1)", b"a/b/c"));
+        assert!(connection
+            .query_row("SELECT regexp('(', 'a')", rusqlite::NO_PARAMS, |row| {
+                row.get::<_, bool>(0)
+            })
+            .is_err());
+    }
+}
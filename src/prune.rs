@@ -0,0 +1,339 @@
+use crate::find::WalkError;
+use crate::mixed::MixedString;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::Metadata;
+use std::io;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Rules that exclude parts of a `Find` volume from indexing.
+///
+/// They are stored in `volumes.settings`, one rule per line:
+/// ```text
+/// exclude <gitignore-style pattern>
+/// ignore-file <name of per-directory file with gitignore-style patterns>
+/// one-file-system
+/// max-depth <depth>
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneRules {
+    pub exclude: Vec<String>,
+    pub ignore_files: Vec<String>,
+    pub one_file_system: bool,
+    pub max_depth: Option<usize>,
+}
+
+impl FromStr for PruneRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Self::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(' ')
+                .map_or((line, ""), |(key, value)| (key, value.trim_start()));
+            match (key, value) {
+                ("exclude", pattern) if !pattern.is_empty() => {
+                    GitignoreBuilder::new("")
+                        .add_line(None, pattern)
+                        .map_err(|e| format!("Line {}: {}", i + 1, e))?;
+                    rules.exclude.push(pattern.to_string());
+                }
+                ("ignore-file", name) if !name.is_empty() && !name.contains('/') => {
+                    rules.ignore_files.push(name.to_string());
+                }
+                ("one-file-system", "") => rules.one_file_system = true,
+                ("max-depth", depth) => {
+                    let depth = depth
+                        .parse()
+                        .map_err(|e| format!("Line {}: invalid depth: {}", i + 1, e))?;
+                    rules.max_depth = Some(depth);
+                }
+                _ => return Err(format!("Line {}: unknown rule `{}`", i + 1, line)),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+impl fmt::Display for PruneRules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for pattern in &self.exclude {
+            writeln!(f, "exclude {pattern}")?;
+        }
+        for name in &self.ignore_files {
+            writeln!(f, "ignore-file {name}")?;
+        }
+        if self.one_file_system {
+            writeln!(f, "one-file-system")?;
+        }
+        if let Some(depth) = self.max_depth {
+            writeln!(f, "max-depth {depth}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do with an entry found while walking
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Index the entry and descend into it
+    Keep,
+    /// Index the entry, but do not read its contents
+    Leaf,
+    /// Skip the entry and everything inside of it
+    Exclude,
+}
+
+/// Applies `PruneRules` to the entries of a single volume
+pub struct Pruner {
+    root: PathBuf,
+    rules: PruneRules,
+    exclude: Gitignore,
+    device: Option<u64>,
+    /// Parsed ignore files of directories, `None` if directory has none
+    ignores: HashMap<PathBuf, Option<Gitignore>>,
+    errors: Vec<WalkError>,
+}
+
+impl Pruner {
+    pub fn new(root: &Path, rules: PruneRules) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in &rules.exclude {
+            // Patterns are validated when rules are parsed
+            let _ = builder.add_line(None, pattern);
+        }
+        let exclude = builder.build().unwrap_or_else(|_| Gitignore::empty());
+        let device = if rules.one_file_system {
+            root.symlink_metadata().ok().map(|meta| meta.st_dev())
+        } else {
+            None
+        };
+        Self {
+            root: root.to_path_buf(),
+            rules,
+            exclude,
+            device,
+            ignores: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub const fn rules(&self) -> &PruneRules {
+        &self.rules
+    }
+
+    /// Returns errors that occurred while reading ignore files
+    pub fn take_errors(&mut self) -> Vec<WalkError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Whether `name` is one of the per-directory ignore files
+    pub fn is_ignore_file(&self, name: &std::ffi::OsStr) -> bool {
+        self.rules
+            .ignore_files
+            .iter()
+            .any(|file| file.as_bytes() == name.as_bytes())
+    }
+
+    /// Drops cached ignore files of `dir`, so they will be read again
+    pub fn forget(&mut self, dir: &Path) {
+        self.ignores.remove(dir);
+    }
+
+    fn load_ignores(&mut self, dir: &Path) {
+        if self.rules.ignore_files.is_empty() || self.ignores.contains_key(dir) {
+            return;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in &self.rules.ignore_files {
+            let file = dir.join(name);
+            if !file.is_file() {
+                continue;
+            }
+            found = true;
+            if let Some(err) = builder.add(&file) {
+                self.errors.push(WalkError {
+                    path: MixedString::from_bytes(file.as_os_str().as_bytes()),
+                    error: io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+                });
+            }
+        }
+        let ignore = if found { builder.build().ok() } else { None };
+        self.ignores.insert(dir.to_path_buf(), ignore);
+    }
+
+    fn is_matched(&mut self, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        if relative.as_os_str().is_empty() {
+            // Root is never excluded
+            return false;
+        }
+
+        let ancestors: Vec<PathBuf> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .map(Path::to_path_buf)
+            .collect();
+        for dir in &ancestors {
+            self.load_ignores(dir);
+        }
+
+        // The deepest ignore file wins, rules of the volume are checked last
+        let ignores = ancestors
+            .iter()
+            .filter_map(|dir| self.ignores.get(dir).and_then(Option::as_ref))
+            .chain(std::iter::once(&self.exclude));
+        for ignore in ignores {
+            match ignore.matched(path, is_dir) {
+                Match::None => {}
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+
+    /// Decides what to do with `path`. Its parent must be already kept
+    pub fn check(&mut self, path: &Path, meta: &Metadata) -> Verdict {
+        let depth = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative.components().count(),
+            Err(_) => return Verdict::Exclude,
+        };
+        if let Some(max_depth) = self.rules.max_depth {
+            if depth > max_depth {
+                return Verdict::Exclude;
+            }
+        }
+        if self.is_matched(path, meta.is_dir()) {
+            return Verdict::Exclude;
+        }
+        if let Some(device) = self.device {
+            if meta.st_dev() != device {
+                // Mount point itself is kept, but not its contents
+                return Verdict::Leaf;
+            }
+        }
+        if self.rules.max_depth == Some(depth) {
+            return Verdict::Leaf;
+        }
+        Verdict::Keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PruneRules, Pruner, Verdict};
+    use std::fs;
+    use std::path::Path;
+
+    fn check(pruner: &mut Pruner, path: &Path) -> Verdict {
+        let meta = path.symlink_metadata().unwrap();
+        pruner.check(path, &meta)
+    }
+
+    #[test]
+    fn parse() {
+        let text = "exclude node_modules\nexclude /.git/objects\nignore-file .gitignore\none-file-system\nmax-depth 5\n";
+        let rules: PruneRules = text.parse().unwrap();
+        assert_eq!(rules.exclude, vec!["node_modules", "/.git/objects"]);
+        assert_eq!(rules.ignore_files, vec![".gitignore"]);
+        assert!(rules.one_file_system);
+        assert_eq!(rules.max_depth, Some(5));
+        assert_eq!(rules.to_string(), text);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("max-depth many".parse::<PruneRules>().is_err());
+        assert!("unknown".parse::<PruneRules>().is_err());
+        assert!("exclude".parse::<PruneRules>().is_err());
+        assert!("ignore-file a/b".parse::<PruneRules>().is_err());
+        assert!("exclude a[".parse::<PruneRules>().is_err());
+    }
+
+    #[test]
+    fn exclude() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("a/node_modules")).unwrap();
+        fs::create_dir_all(root.join(".git/objects")).unwrap();
+        fs::create_dir_all(root.join("b/.git/objects")).unwrap();
+
+        let rules = "exclude node_modules\nexclude /.git/objects"
+            .parse()
+            .unwrap();
+        let mut pruner = Pruner::new(root, rules);
+        assert_eq!(check(&mut pruner, root), Verdict::Keep);
+        assert_eq!(check(&mut pruner, &root.join("a")), Verdict::Keep);
+        assert_eq!(
+            check(&mut pruner, &root.join("a/node_modules")),
+            Verdict::Exclude
+        );
+        assert_eq!(
+            check(&mut pruner, &root.join(".git/objects")),
+            Verdict::Exclude
+        );
+        assert_eq!(
+            check(&mut pruner, &root.join("b/.git/objects")),
+            Verdict::Keep
+        );
+    }
+
+    #[test]
+    fn ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("project/target")).unwrap();
+        fs::create_dir_all(root.join("project/sub")).unwrap();
+        fs::write(root.join("project/.gitignore"), "target\n*.log\n").unwrap();
+        fs::write(root.join("project/sub/.gitignore"), "!keep.log\n").unwrap();
+        fs::write(root.join("project/sub/drop.log"), "").unwrap();
+        fs::write(root.join("project/sub/keep.log"), "").unwrap();
+        fs::write(root.join("top.log"), "").unwrap();
+
+        let rules = "ignore-file .gitignore".parse().unwrap();
+        let mut pruner = Pruner::new(root, rules);
+        let project = root.join("project");
+        assert_eq!(
+            check(&mut pruner, &project.join("target")),
+            Verdict::Exclude
+        );
+        assert_eq!(
+            check(&mut pruner, &project.join("sub/drop.log")),
+            Verdict::Exclude
+        );
+        assert_eq!(
+            check(&mut pruner, &project.join("sub/keep.log")),
+            Verdict::Keep
+        );
+        assert_eq!(check(&mut pruner, &root.join("top.log")), Verdict::Keep);
+        assert!(pruner.take_errors().is_empty());
+    }
+
+    #[test]
+    fn max_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+
+        let rules = "max-depth 2".parse().unwrap();
+        let mut pruner = Pruner::new(root, rules);
+        assert_eq!(check(&mut pruner, &root.join("a")), Verdict::Keep);
+        assert_eq!(check(&mut pruner, &root.join("a/b")), Verdict::Leaf);
+        assert_eq!(check(&mut pruner, &root.join("a/b/c")), Verdict::Exclude);
+    }
+}
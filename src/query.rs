@@ -0,0 +1,754 @@
+use pest::iterators::Pair;
+use pest::Parser;
+use pest_derive::Parser;
+use std::fmt;
+
+#[derive(Parser)]
+#[grammar = "query.pest"]
+struct QueryParser;
+
+pub type Error = pest::error::Error<Rule>;
+
+/// Byte offsets of a node in the query text
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Bare or quoted word. Escaped quotes and backslashes are already replaced in `text`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Value {
+    pub text: String,
+    pub quoted: bool,
+    pub span: Span,
+}
+
+/// `name` or `name=value`, applied to a command or an expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Modifier {
+    pub name: Value,
+    pub value: Option<Value>,
+    pub span: Span,
+}
+
+/// Positional `value` or named `name=value` argument of a function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Argument {
+    pub name: Option<Value>,
+    pub value: Value,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandKind {
+    /// Single value, matched against paths
+    Term(Value),
+    /// `left op right`
+    Compare { left: Value, op: Op, right: Value },
+    /// `{name args...}`
+    Macro { name: Value, args: Vec<Value> },
+    /// `name(args...)`
+    Function { name: Value, args: Vec<Argument> },
+}
+
+/// Comparison operator
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn parse(op: &str) -> Self {
+        match op {
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            _ => Self::Eq,
+        }
+    }
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Eq => "=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        }
+    }
+
+    /// Operator with swapped sides, `a < b` is the same as `b > a`
+    pub const fn swapped(self) -> Self {
+        match self {
+            Self::Eq => Self::Eq,
+            Self::Lt => Self::Gt,
+            Self::Le => Self::Ge,
+            Self::Gt => Self::Lt,
+            Self::Ge => Self::Le,
+        }
+    }
+}
+
+/// `modifiers: command`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Command {
+    pub modifiers: Vec<Modifier>,
+    pub kind: CommandKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExprKind {
+    Or(Vec<Expression>),
+    And(Vec<Expression>),
+    Command(Command),
+    /// `!expression` or `not(expression)`. Modifiers inside of it are parsed, but rejected
+    /// by the compiler: `!i: a` could be both the complement of `i: a` and `i: !a`
+    Not(Box<Expression>),
+    /// `modifiers; expression`
+    Modified {
+        modifiers: Vec<Modifier>,
+        expr: Box<Expression>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expression {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+/// `modifiers; expression`, where modifiers are applied to the whole query
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+    pub modifiers: Vec<Modifier>,
+    pub expr: Expression,
+    pub span: Span,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span) -> Self {
+        Self {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+/// Replaces `\"` and `\\` of quoted string by the quote and the backslash,
+/// other backslashes are kept as they are
+fn unescape(string: &str) -> String {
+    let mut res = String::with_capacity(string.len());
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next)) if next == '"' || next == '\\' => {
+                res.push(next);
+                chars.next();
+            }
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+impl Value {
+    /// Raw bytes of the value. `\u{xx}` stands for byte `xx`, the same way as in printed paths,
+    /// and the mark of truncated character is skipped
+    pub fn to_bytes(&self) -> Vec<u8> {
+        const TRUNCATED: &str = "\u{FFDD}";
+        let text = self.text.as_bytes();
+        let mut res = Vec::with_capacity(text.len());
+        let mut i = 0;
+        while i < text.len() {
+            if text[i..].starts_with(TRUNCATED.as_bytes()) {
+                i += TRUNCATED.len();
+                continue;
+            }
+            let escaped = text.get(i..i + 6).and_then(|escape| {
+                if escape.starts_with(b"\\u{") && escape[5] == b'}' {
+                    let hex = std::str::from_utf8(&escape[3..5]).ok()?;
+                    u8::from_str_radix(hex, 16).ok()
+                } else {
+                    None
+                }
+            });
+            if let Some(byte) = escaped {
+                res.push(byte);
+                i += 6;
+            } else {
+                res.push(text[i]);
+                i += 1;
+            }
+        }
+        res
+    }
+
+    fn parse(pair: Pair<Rule>) -> Self {
+        let span = pair.as_span().into();
+        match pair.as_rule() {
+            Rule::quoted => {
+                let string = pair.into_inner().next().map_or("", |inner| inner.as_str());
+                Self {
+                    text: unescape(string),
+                    quoted: true,
+                    span,
+                }
+            }
+            _ => Self {
+                text: pair.as_str().to_string(),
+                quoted: false,
+                span,
+            },
+        }
+    }
+}
+
+/// `name` and optional `value` of `eq_or_value` rule
+fn eq_or_value(pair: Pair<Rule>) -> (Value, Option<Value>) {
+    let inner = pair
+        .into_inner()
+        .next()
+        .expect("eq_or_value is never empty");
+    match inner.as_rule() {
+        Rule::eq => {
+            let mut values = inner.into_inner().map(Value::parse);
+            let name = values.next().expect("eq has left side");
+            (name, values.next())
+        }
+        _ => (Value::parse(inner), None),
+    }
+}
+
+impl Modifier {
+    fn parse(pair: Pair<Rule>) -> Self {
+        let span = pair.as_span().into();
+        let (name, value) = eq_or_value(pair);
+        Self { name, value, span }
+    }
+}
+
+impl Argument {
+    fn parse(pair: Pair<Rule>) -> Self {
+        let span = pair.as_span().into();
+        match eq_or_value(pair) {
+            (name, Some(value)) => Self {
+                name: Some(name),
+                value,
+                span,
+            },
+            (value, None) => Self {
+                name: None,
+                value,
+                span,
+            },
+        }
+    }
+}
+
+impl Command {
+    fn parse(pair: Pair<Rule>) -> Self {
+        let span = pair.as_span().into();
+        let mut modifiers = Vec::new();
+        let mut kind = None;
+        for inner in pair.into_inner() {
+            match inner.as_rule() {
+                Rule::modifier => modifiers.push(Modifier::parse(inner)),
+                Rule::term => {
+                    let value = inner.into_inner().next().expect("term has value");
+                    kind = Some(CommandKind::Term(Value::parse(value)));
+                }
+                Rule::compare => {
+                    let mut inner = inner.into_inner();
+                    let left = Value::parse(inner.next().expect("compare has left side"));
+                    let op = Op::parse(inner.next().expect("compare has operator").as_str());
+                    let right = Value::parse(inner.next().expect("compare has right side"));
+                    kind = Some(CommandKind::Compare { left, op, right });
+                }
+                Rule::macros => {
+                    let mut values = inner.into_inner().map(Value::parse);
+                    let name = values.next().expect("macros has name");
+                    kind = Some(CommandKind::Macro {
+                        name,
+                        args: values.collect(),
+                    });
+                }
+                Rule::function => {
+                    let mut inner = inner.into_inner();
+                    let name = Value::parse(inner.next().expect("function has name"));
+                    kind = Some(CommandKind::Function {
+                        name,
+                        args: inner.map(Argument::parse).collect(),
+                    });
+                }
+                rule => unreachable!("Unexpected {:?} in command", rule),
+            }
+        }
+        Self {
+            modifiers,
+            kind: kind.expect("command has expression"),
+            span,
+        }
+    }
+}
+
+impl Expression {
+    /// Parses `expression`, `and`, `not` and `braces` rules. Single-element `&&` and `||` are flattened
+    fn parse(pair: Pair<Rule>) -> Self {
+        let span: Span = pair.as_span().into();
+        match pair.as_rule() {
+            Rule::expression | Rule::and => {
+                let is_or = pair.as_rule() == Rule::expression;
+                let mut items: Vec<Self> = pair.into_inner().map(Self::parse).collect();
+                if items.len() == 1 {
+                    return items.pop().expect("checked length");
+                }
+                let kind = if is_or {
+                    ExprKind::Or(items)
+                } else {
+                    ExprKind::And(items)
+                };
+                Self { kind, span }
+            }
+            Rule::not => {
+                let inner = pair.into_inner().next().expect("not has expression");
+                Self {
+                    kind: ExprKind::Not(Box::new(Self::parse(inner))),
+                    span,
+                }
+            }
+            Rule::braces => {
+                let mut modifiers = Vec::new();
+                let mut expr = None;
+                for inner in pair.into_inner() {
+                    match inner.as_rule() {
+                        Rule::modifier => modifiers.push(Modifier::parse(inner)),
+                        Rule::command => {
+                            let command = Command::parse(inner);
+                            expr = Some(Self {
+                                span: command.span,
+                                kind: ExprKind::Command(command),
+                            });
+                        }
+                        _ => expr = Some(Self::parse(inner)),
+                    }
+                }
+                let expr = expr.expect("braces has expression");
+                if modifiers.is_empty() {
+                    return expr;
+                }
+                Self {
+                    kind: ExprKind::Modified {
+                        modifiers,
+                        expr: Box::new(expr),
+                    },
+                    span,
+                }
+            }
+            rule => unreachable!("Unexpected {:?} in expression", rule),
+        }
+    }
+}
+
+impl Expression {
+    /// Commands of the expression, in order of appearance
+    fn commands(&self) -> Vec<&Command> {
+        match &self.kind {
+            ExprKind::Or(items) | ExprKind::And(items) => {
+                items.iter().flat_map(Self::commands).collect()
+            }
+            ExprKind::Not(expr) | ExprKind::Modified { expr, .. } => expr.commands(),
+            ExprKind::Command(command) => vec![command],
+        }
+    }
+
+    /// Names of macros referenced by the expression as `{name}`, in order of appearance
+    pub fn macros(&self) -> Vec<&str> {
+        self.commands()
+            .into_iter()
+            .filter_map(|command| match &command.kind {
+                CommandKind::Macro { name, .. } => Some(name.text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Names of functions called by the expression, in order of appearance. Macros
+    /// with parameters may be called as functions too
+    pub fn functions(&self) -> Vec<&str> {
+        self.commands()
+            .into_iter()
+            .filter_map(|command| match &command.kind {
+                CommandKind::Function { name, .. } => Some(name.text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Parses query text into AST
+pub fn parse(input: &str) -> Result<Query, Error> {
+    let pairs = QueryParser::parse(Rule::entry_point, input)?;
+    let mut modifiers = Vec::new();
+    let mut expr = None;
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::modifier => modifiers.push(Modifier::parse(pair)),
+            Rule::expression => expr = Some(Expression::parse(pair)),
+            _ => {}
+        }
+    }
+    Ok(Query {
+        modifiers,
+        expr: expr.expect("entry point has expression"),
+        span: Span {
+            start: 0,
+            end: input.len(),
+        },
+    })
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {}", self.text, self.span)
+    }
+}
+
+fn write_modifiers(f: &mut fmt::Formatter, modifiers: &[Modifier], indent: usize) -> fmt::Result {
+    for modifier in modifiers {
+        match &modifier.value {
+            Some(value) => writeln!(
+                f,
+                "{:indent$}modifier {} = {}",
+                "",
+                modifier.name,
+                value,
+                indent = indent
+            )?,
+            None => writeln!(
+                f,
+                "{:indent$}modifier {}",
+                "",
+                modifier.name,
+                indent = indent
+            )?,
+        }
+    }
+    Ok(())
+}
+
+impl Command {
+    fn write_tree(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        writeln!(f, "{:indent$}command {}", "", self.span, indent = indent)?;
+        let indent = indent + 2;
+        write_modifiers(f, &self.modifiers, indent)?;
+        match &self.kind {
+            CommandKind::Term(value) => {
+                writeln!(f, "{:indent$}term {}", "", value, indent = indent)
+            }
+            CommandKind::Compare { left, op, right } => writeln!(
+                f,
+                "{:indent$}compare {} {} {}",
+                "",
+                left,
+                op.as_str(),
+                right,
+                indent = indent
+            ),
+            CommandKind::Macro { name, args } => {
+                writeln!(f, "{:indent$}macro {}", "", name, indent = indent)?;
+                for arg in args {
+                    writeln!(f, "{:indent$}argument {}", "", arg, indent = indent + 2)?;
+                }
+                Ok(())
+            }
+            CommandKind::Function { name, args } => {
+                writeln!(f, "{:indent$}function {}", "", name, indent = indent)?;
+                for arg in args {
+                    match &arg.name {
+                        Some(name) => writeln!(
+                            f,
+                            "{:indent$}argument {} = {}",
+                            "",
+                            name,
+                            arg.value,
+                            indent = indent + 2
+                        )?,
+                        None => writeln!(
+                            f,
+                            "{:indent$}argument {}",
+                            "",
+                            arg.value,
+                            indent = indent + 2
+                        )?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Expression {
+    fn write_tree(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let (name, items) = match &self.kind {
+            ExprKind::Or(items) => ("or", items),
+            ExprKind::And(items) => ("and", items),
+            ExprKind::Command(command) => return command.write_tree(f, indent),
+            ExprKind::Not(expr) => {
+                writeln!(f, "{:indent$}not {}", "", self.span, indent = indent)?;
+                return expr.write_tree(f, indent + 2);
+            }
+            ExprKind::Modified { modifiers, expr } => {
+                writeln!(f, "{:indent$}modified {}", "", self.span, indent = indent)?;
+                write_modifiers(f, modifiers, indent + 2)?;
+                return expr.write_tree(f, indent + 2);
+            }
+        };
+        writeln!(f, "{:indent$}{} {}", "", name, self.span, indent = indent)?;
+        for item in items {
+            item.write_tree(f, indent + 2)?;
+        }
+        Ok(())
+    }
+}
+
+/// Indented tree of the query
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "query {}", self.span)?;
+        write_modifiers(f, &self.modifiers, 2)?;
+        self.expr.write_tree(f, 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, CommandKind, ExprKind, Expression, Span, Value};
+
+    /// Short form of the expression: `(a || (b && c))`
+    fn shape(expr: &Expression) -> String {
+        let join = |items: &[Expression], sep: &str| {
+            let items: Vec<String> = items.iter().map(shape).collect();
+            format!("({})", items.join(sep))
+        };
+        match &expr.kind {
+            ExprKind::Or(items) => join(items, " || "),
+            ExprKind::And(items) => join(items, " && "),
+            ExprKind::Not(expr) => format!("!{}", shape(expr)),
+            ExprKind::Modified { modifiers, expr } => {
+                let names: Vec<&str> = modifiers.iter().map(|m| m.name.text.as_str()).collect();
+                format!("[{}] {}", names.join(","), shape(expr))
+            }
+            ExprKind::Command(command) => {
+                let names: Vec<&str> = command
+                    .modifiers
+                    .iter()
+                    .map(|m| m.name.text.as_str())
+                    .collect();
+                let prefix = if names.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}: ", names.join(","))
+                };
+                let body = match &command.kind {
+                    CommandKind::Term(value) => value.text.clone(),
+                    CommandKind::Compare { left, op, right } => {
+                        format!("{}{}{}", left.text, op.as_str(), right.text)
+                    }
+                    CommandKind::Macro { name, args } => {
+                        let args: Vec<&str> = args.iter().map(|a| a.text.as_str()).collect();
+                        format!("{{{} {}}}", name.text, args.join(" "))
+                    }
+                    CommandKind::Function { name, args } => {
+                        let args: Vec<String> = args
+                            .iter()
+                            .map(|a| {
+                                a.name.as_ref().map_or_else(
+                                    || a.value.text.clone(),
+                                    |name| format!("{}={}", name.text, a.value.text),
+                                )
+                            })
+                            .collect();
+                        format!("{}({})", name.text, args.join(","))
+                    }
+                };
+                prefix + &body
+            }
+        }
+    }
+
+    fn parsed(input: &str) -> String {
+        shape(&parse(input).unwrap().expr)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parsed("a || b && c"), "(a || (b && c))");
+        assert_eq!(parsed("a && b || c"), "((a && b) || c)");
+        assert_eq!(parsed("(a || b) && c"), "((a || b) && c)");
+        assert_eq!(parsed("a && (b || (c && d))"), "(a && (b || (c && d)))");
+        assert_eq!(parsed("((a))"), "a");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(parsed(r#""a b" && "x||y""#), "(a b && x||y)");
+        assert_eq!(parsed(r#""(" || ")""#), "(( || ))");
+        assert_eq!(parsed(r#""""#), "");
+        assert_eq!(parsed("*.mkv && src/main.rs"), "(*.mkv && src/main.rs)");
+
+        let query = parse(r#"  "a b"  "#).unwrap();
+        match query.expr.kind {
+            ExprKind::Command(command) => assert_eq!(
+                command.kind,
+                CommandKind::Term(Value {
+                    text: "a b".to_string(),
+                    quoted: true,
+                    span: Span { start: 2, end: 7 },
+                })
+            ),
+            kind => panic!("Unexpected {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn escaped_quotes() {
+        assert_eq!(parsed(r#""say \"hi\"""#), r#"say "hi""#);
+        assert_eq!(parsed(r#""\"" && b"#), r#"(" && b)"#);
+        // Backslash is escaping only quotes and itself
+        assert_eq!(parsed(r#""a\b""#), r"a\b");
+        assert_eq!(parsed(r#""C:\\""#), r"C:\");
+        assert_eq!(parsed(r#""a\\\"b""#), r#"a\"b"#);
+        assert_eq!(
+            parsed(r#"path_glob("dir\\") && b"#),
+            r"(path_glob(dir\) && b)"
+        );
+        assert!(parse(r#""unterminated"#).is_err());
+        assert!(parse(r#""escaped end\""#).is_err());
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parsed("type=dir"), "type=dir");
+        assert_eq!(parsed(r#"name = "a b""#), "name=a b");
+        assert_eq!(
+            parsed("size>100M && mtime<=7d || atime>=2024-01-01 && depth<3"),
+            "((size>100M && mtime<=7d) || (atime>=2024-01-01 && depth<3))"
+        );
+        assert_eq!(parsed(r#""a<b""#), "a<b");
+        assert_eq!(parsed("{big 500M /data}"), "{big 500M /data}");
+        assert_eq!(parsed("size(1, unit=k,)"), "size(1,unit=k)");
+        assert_eq!(parsed("empty()"), "empty()");
+        assert_eq!(parsed("i, case=no: foo"), "i,case: foo");
+        assert_eq!(
+            parsed("x || i; (a || b) && c"),
+            "(x || ([i] (a || b) && c))"
+        );
+        assert_eq!(parsed("a && i; b"), "(a && [i] b)");
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(parsed("!a"), "!a");
+        assert_eq!(parsed("!a && b"), "(!a && b)");
+        assert_eq!(parsed("a || !b && c"), "(a || (!b && c))");
+        assert_eq!(parsed("!(a || b)"), "!(a || b)");
+        assert_eq!(parsed("not(a || b) && c"), "(!(a || b) && c)");
+        assert_eq!(parsed("!!a"), "!!a");
+        assert_eq!(parsed("!size>1M"), "!size>1M");
+        assert_eq!(parsed("!{big}"), "!{big }");
+        assert_eq!(parsed("!i: a"), "!i: a");
+        assert_eq!(parsed("x && i; !a"), "(x && [i] !a)");
+        assert_eq!(parsed("x && !i; a"), "(x && ![i] a)");
+        // Only `not` followed by parentheses is negation
+        assert_eq!(parsed("not && nothing"), "(not && nothing)");
+        assert_eq!(parsed(r#""!a""#), "!a");
+        assert!(parse("a !b").is_err());
+        assert!(parse("!").is_err());
+        assert!(parse("not(a").is_err());
+
+        let query = parse("a && !b").unwrap();
+        match query.expr.kind {
+            ExprKind::And(items) => assert_eq!(items[1].span, Span { start: 5, end: 7 }),
+            kind => panic!("Unexpected {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn macro_names() {
+        let query = parse("{a} || !({b 1} && c) && i; {a}").unwrap();
+        assert_eq!(query.expr.macros(), vec!["a", "b", "a"]);
+        assert!(parse("a && f(x)").unwrap().expr.macros().is_empty());
+        let query = parse("f(x) || !(a && {b} && g(y))").unwrap();
+        assert_eq!(query.expr.functions(), vec!["f", "g"]);
+    }
+
+    #[test]
+    fn top_level_modifiers() {
+        let query = parse("i, tz=UTC; a").unwrap();
+        assert_eq!(query.modifiers.len(), 2);
+        assert_eq!(query.modifiers[0].name.text, "i");
+        assert_eq!(query.modifiers[0].value, None);
+        assert_eq!(query.modifiers[1].value.as_ref().unwrap().text, "UTC");
+        assert_eq!(query.modifiers[1].span, Span { start: 3, end: 9 });
+        assert_eq!(shape(&query.expr), "a");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("").is_err());
+        assert!(parse("a b").is_err());
+        assert!(parse("a &&").is_err());
+        assert!(parse("(a").is_err());
+        assert!(parse("{}").is_err());
+        assert!(parse("size>>1").is_err());
+        assert!(parse("size=>1").is_err());
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let query = parse("\"a\\u{80}b\\u{zz}\\u{e4}\u{FFDD}\"").unwrap();
+        match query.expr.kind {
+            ExprKind::Command(command) => match command.kind {
+                CommandKind::Term(value) => {
+                    assert_eq!(value.to_bytes(), b"a\x80b\\u{zz}\xe4".to_vec());
+                }
+                kind => panic!("Unexpected {:?}", kind),
+            },
+            kind => panic!("Unexpected {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn explain() {
+        let tree = parse("i; a && f(x, k=v) || {m 1}").unwrap().to_string();
+        let expected = r#"query 0..26
+  modifier "i" 0..1
+  or 3..26
+    and 3..17
+      command 3..4
+        term "a" 3..4
+      command 8..17
+        function "f" 8..9
+          argument "x" 10..11
+          argument "k" 13..14 = "v" 15..16
+    command 21..26
+      macro "m" 22..23
+        argument "1" 24..25
+"#;
+        assert_eq!(tree, expected);
+    }
+}
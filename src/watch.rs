@@ -0,0 +1,631 @@
+use crate::database::{Database, Precompiled};
+use crate::find::{self, WalkError};
+use crate::mixed::MixedString;
+use crate::model::{SubvolumeInfo, SubvolumeSource};
+use crate::prune::{PruneRules, Pruner, Verdict};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Database(rusqlite::Error),
+    /// Watched directory is not a registered volume
+    NotRegistered(PathBuf),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "IO error: {e}"),
+            Self::Database(e) => write!(f, "Database error: {e}"),
+            Self::NotRegistered(path) => write!(f, "Directory {} is not added", path.display()),
+        }
+    }
+}
+
+pub struct Settings {
+    /// Changes are collected during this interval and then committed at once
+    pub debounce: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_secs(2),
+        }
+    }
+}
+
+fn to_mixed(path: &Path) -> MixedString {
+    MixedString::from_bytes(path.as_os_str().as_bytes())
+}
+
+/// Changes of single volume, that are not committed yet
+#[derive(Default)]
+struct Batch {
+    /// Files that were created, modified or removed. Actual state is read when committing
+    dirty: HashSet<PathBuf>,
+    /// Subtrees that should be walked again from scratch
+    rescan: HashSet<PathBuf>,
+}
+
+impl Batch {
+    fn is_empty(&self) -> bool {
+        self.dirty.is_empty() && self.rescan.is_empty()
+    }
+
+    fn is_rescanned(&self, path: &Path) -> bool {
+        path.ancestors()
+            .skip(1)
+            .any(|parent| self.rescan.contains(parent))
+    }
+
+    /// Converts collected changes to the delta of volume, also returns errors of unreadable files.
+    /// `indexed` must return all indexed paths inside of the directory
+    fn into_delta<F>(
+        self,
+        root: &Path,
+        rules: &PruneRules,
+        mut indexed: F,
+    ) -> Result<(SubvolumeInfo, Vec<WalkError>), Error>
+    where
+        F: FnMut(&MixedString) -> Result<Vec<MixedString>, Error>,
+    {
+        let mut files = HashMap::new();
+        let mut errors = Vec::new();
+
+        for path in &self.dirty {
+            if self.rescan.contains(path) || self.is_rescanned(path) {
+                continue;
+            }
+            let mixed = find::relative(root, path);
+            match path.symlink_metadata() {
+                Ok(meta) => {
+                    let info = find::file_info(mixed.clone(), &meta);
+                    files.insert(mixed, Some(info));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    // Removed directory may still have indexed children, if it was moved away
+                    for child in indexed(&mixed)? {
+                        files.insert(child, None);
+                    }
+                    files.insert(mixed, None);
+                }
+                Err(error) => errors.push(WalkError {
+                    path: to_mixed(path),
+                    error,
+                }),
+            }
+        }
+
+        for subtree in &self.rescan {
+            if self.is_rescanned(subtree) {
+                continue;
+            }
+            let mixed = find::relative(root, subtree);
+            // Indexed state is kept as is, if the root itself is unavailable
+            let (found, walk_errors) = match find::walk_subtree(to_mixed(root), &mixed, rules) {
+                Ok(walked) => walked,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            // Contents of unreadable directories are kept as is
+            let mut failed = Vec::new();
+            for err in walk_errors {
+                if err.error.kind() != io::ErrorKind::NotFound {
+                    let mut prefix = find::relative(root, &find::to_path(&err.path)).to_bytes();
+                    // Everything is inside of the root
+                    if !prefix.is_empty() {
+                        prefix.push(b'/');
+                    }
+                    failed.push(prefix);
+                    errors.push(err);
+                }
+            }
+            let found = found.files;
+            if !found.contains_key(&mixed) {
+                files.insert(mixed.clone(), None);
+            }
+            for child in indexed(&mixed)? {
+                let bytes = child.to_bytes();
+                if !failed.iter().any(|prefix| bytes.starts_with(prefix)) {
+                    files.insert(child, None);
+                }
+            }
+            files.extend(found);
+        }
+
+        let delta = SubvolumeInfo {
+            source: SubvolumeSource::Find {
+                path: to_mixed(root),
+            },
+            overwrite: false,
+            files,
+        };
+        Ok((delta, errors))
+    }
+}
+
+/// Keeps `Find` volumes up to date using inotify
+pub struct Watcher {
+    inotify: Inotify,
+    settings: Settings,
+    roots: Vec<PathBuf>,
+    pruners: Vec<Pruner>,
+    /// Watched directory and index of the root it belongs to
+    watches: HashMap<WatchDescriptor, (usize, PathBuf)>,
+    pending: Vec<Batch>,
+    batch_started: Option<Instant>,
+    /// Errors that did not stop watching, they are reported by `run`
+    errors: Vec<WalkError>,
+}
+
+impl Watcher {
+    pub fn new(settings: Settings, roots: Vec<(PathBuf, PruneRules)>) -> io::Result<Self> {
+        let pruners = roots
+            .iter()
+            .map(|(root, rules)| Pruner::new(root, rules.clone()))
+            .collect();
+        let mut watcher = Self {
+            inotify: Inotify::init()?,
+            settings,
+            pending: roots.iter().map(|_| Batch::default()).collect(),
+            roots: roots.into_iter().map(|(root, _)| root).collect(),
+            pruners,
+            watches: HashMap::new(),
+            batch_started: None,
+            errors: Vec::new(),
+        };
+        for root in 0..watcher.roots.len() {
+            let path = watcher.roots[root].clone();
+            watcher.add_watches(root, &path);
+        }
+        Ok(watcher)
+    }
+
+    /// Recursively watches every directory inside of `path`, that is not pruned
+    fn add_watches(&mut self, root: usize, path: &Path) {
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::DONT_FOLLOW
+            | WatchMask::ONLYDIR;
+        let mut walker = WalkDir::new(path).into_iter();
+        while let Some(entry) = walker.next() {
+            let dir = match entry {
+                Ok(entry) if entry.file_type().is_dir() => entry,
+                _ => continue,
+            };
+            let verdict = match dir.metadata() {
+                Ok(meta) => self.pruners[root].check(dir.path(), &meta),
+                Err(_) => Verdict::Exclude,
+            };
+            if verdict != Verdict::Keep {
+                walker.skip_current_dir();
+                continue;
+            }
+            match self.inotify.add_watch(dir.path(), mask) {
+                Ok(wd) => {
+                    self.watches.insert(wd, (root, dir.path().to_path_buf()));
+                }
+                Err(error) => self.errors.push(WalkError {
+                    path: to_mixed(dir.path()),
+                    error,
+                }),
+            }
+        }
+        let errors = self.pruners[root].take_errors();
+        self.errors.extend(errors);
+    }
+
+    /// Stops watching `path` and every directory inside of it
+    fn remove_watches(&mut self, path: &Path) {
+        let removed: Vec<WatchDescriptor> = self
+            .watches
+            .iter()
+            .filter(|(_, (_, dir))| dir.starts_with(path))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in removed {
+            self.watches.remove(&wd);
+            // Watch is already gone if directory was removed
+            let _ = self.inotify.rm_watch(wd);
+        }
+    }
+
+    fn handle(&mut self, wd: &WatchDescriptor, mask: EventMask, name: Option<&OsStr>) {
+        if mask.contains(EventMask::Q_OVERFLOW) {
+            // Some events are lost and we don't know which ones, created directories included
+            for root in 0..self.roots.len() {
+                let path = self.roots[root].clone();
+                self.add_watches(root, &path);
+                self.pending[root].rescan.insert(path);
+            }
+            return;
+        }
+
+        let (root, dir) = match self.watches.get(wd) {
+            Some((root, dir)) => (*root, dir.clone()),
+            None => return,
+        };
+        if mask.contains(EventMask::IGNORED) {
+            self.watches.remove(wd);
+            return;
+        }
+        let path = match name {
+            Some(name) => dir.join(name),
+            None => return,
+        };
+
+        if name.is_some_and(|name| self.pruners[root].is_ignore_file(name)) {
+            // Rules are changed, so everything inside should be checked again.
+            // Directories that are not excluded anymore are watched from now on
+            self.pruners[root].forget(&dir);
+            self.add_watches(root, &dir);
+            self.pending[root].rescan.insert(dir);
+        }
+        let verdict = match path.symlink_metadata() {
+            Ok(meta) => self.pruners[root].check(&path, &meta),
+            // Removed files are checked when committing
+            Err(_) => Verdict::Leaf,
+        };
+        if verdict == Verdict::Exclude {
+            return;
+        }
+
+        if mask.contains(EventMask::ISDIR) {
+            if mask.intersects(EventMask::MOVED_FROM | EventMask::DELETE) {
+                self.remove_watches(&path);
+            } else if verdict == Verdict::Keep
+                && mask.intersects(EventMask::CREATE | EventMask::MOVED_TO)
+            {
+                // Files could be created before watch is installed, so whole directory is rescanned
+                self.add_watches(root, &path);
+                self.pending[root].rescan.insert(path.clone());
+            }
+        }
+        self.pending[root].dirty.insert(path);
+    }
+
+    fn read_events(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let events: Vec<_> = self
+            .inotify
+            .read_events(buffer)?
+            .map(|event| (event.wd, event.mask, event.name.map(OsStr::to_os_string)))
+            .collect();
+        for (wd, mask, name) in &events {
+            self.handle(wd, *mask, name.as_deref());
+        }
+        Ok(events.len())
+    }
+
+    /// Blocks until events are available or `timeout` passes. Without `timeout` waits forever
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        // Rounded up, otherwise the last millisecond of debounce is spent spinning
+        let timeout = timeout.map_or(-1, |timeout| {
+            i32::try_from(timeout.as_nanos().div_ceil(1_000_000)).unwrap_or(i32::MAX)
+        });
+        let mut fd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&raw mut fd, 1, timeout) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+            ready => Ok(ready > 0),
+        }
+    }
+
+    /// Writes all pending changes to the database
+    fn commit(&mut self, db: &mut Database, precompiled: &[Precompiled]) -> Result<(), Error> {
+        let pending = std::mem::replace(
+            &mut self.pending,
+            self.roots.iter().map(|_| Batch::default()).collect(),
+        );
+        self.batch_started = None;
+
+        let mut subvolumes = Vec::new();
+        for ((root, pruner), batch) in self.roots.iter().zip(&self.pruners).zip(pending) {
+            if batch.is_empty() {
+                continue;
+            }
+            // Volume could be removed while watching, it must not be registered again
+            let Some(volume) = db.directory_volume(&to_mixed(root))? else {
+                return Err(Error::NotRegistered(root.clone()));
+            };
+            let (delta, errors) = batch.into_delta(root, pruner.rules(), |path| {
+                Ok(db.list_prefix(volume, path)?)
+            })?;
+            subvolumes.push(delta);
+            self.errors.extend(errors);
+        }
+        if !subvolumes.is_empty() {
+            db.insert_data(subvolumes, precompiled)?;
+        }
+        Ok(())
+    }
+
+    /// Processes events until error occurs. Matches of `precompiled` macros are kept up to date.
+    /// Errors of single files and directories are passed to `report`
+    pub fn run<F>(
+        &mut self,
+        db: &mut Database,
+        precompiled: &[Precompiled],
+        mut report: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(WalkError),
+    {
+        let mut buffer = [0; 4096];
+        loop {
+            for err in self.errors.drain(..) {
+                report(err);
+            }
+            let timeout = self
+                .batch_started
+                .map(|started| self.settings.debounce.saturating_sub(started.elapsed()));
+            if self.wait(timeout)? {
+                self.read_events(&mut buffer)?;
+            }
+            let has_pending = self.pending.iter().any(|batch| !batch.is_empty());
+            if has_pending && self.batch_started.is_none() {
+                self.batch_started = Some(Instant::now());
+            }
+            if let Some(started) = self.batch_started {
+                if started.elapsed() >= self.settings.debounce {
+                    self.commit(db, precompiled)?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_mixed, Batch, Error, Settings, Watcher};
+    use crate::database::Database;
+    use crate::mixed::MixedString;
+    use crate::model::SubvolumeSource;
+    use crate::prune::PruneRules;
+    use std::fs;
+    use std::time::Duration;
+
+    fn key(path: &str) -> MixedString {
+        MixedString::from_bytes(path.as_bytes())
+    }
+
+    #[test]
+    fn delta_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"hello").unwrap();
+
+        let mut batch = Batch::default();
+        batch.dirty.insert(file);
+        let (delta, errors) = batch
+            .into_delta(dir.path(), &PruneRules::default(), |_| Ok(Vec::new()))
+            .unwrap();
+        assert!(errors.is_empty());
+
+        assert!(!delta.overwrite);
+        assert_eq!(delta.files.len(), 1);
+        let info = delta.files[&key("file")].as_ref().unwrap();
+        assert_eq!(info.length, 5);
+    }
+
+    #[test]
+    fn delta_removed_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let removed = dir.path().join("removed");
+        let child = key("removed/child");
+
+        let mut batch = Batch::default();
+        batch.dirty.insert(removed);
+        let indexed = vec![child.clone()];
+        let (delta, errors) = batch
+            .into_delta(dir.path(), &PruneRules::default(), |_| Ok(indexed.clone()))
+            .unwrap();
+        assert!(errors.is_empty());
+
+        assert_eq!(delta.files.len(), 2);
+        assert!(delta.files[&key("removed")].is_none());
+        assert!(delta.files[&child].is_none());
+    }
+
+    #[test]
+    fn delta_rescan() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("new"), b"").unwrap();
+        let vanished = key("sub/vanished");
+
+        let mut batch = Batch::default();
+        batch.rescan.insert(sub.clone());
+        // Covered by rescan
+        batch.dirty.insert(sub.join("new"));
+        let indexed = vec![vanished.clone()];
+        let (delta, errors) = batch
+            .into_delta(dir.path(), &PruneRules::default(), |_| Ok(indexed.clone()))
+            .unwrap();
+        assert!(errors.is_empty());
+
+        assert_eq!(delta.files.len(), 3);
+        assert!(delta.files[&key("sub")].is_some());
+        assert!(delta.files[&key("sub/new")].is_some());
+        assert!(delta.files[&vanished].is_none());
+    }
+
+    #[test]
+    fn events() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("existing");
+        fs::create_dir(&existing).unwrap();
+
+        let root = (dir.path().to_path_buf(), PruneRules::default());
+        let mut watcher = Watcher::new(Settings::default(), vec![root]).unwrap();
+        fs::write(existing.join("file"), b"").unwrap();
+        fs::create_dir(dir.path().join("new")).unwrap();
+
+        let mut buffer = [0; 4096];
+        while watcher.read_events(&mut buffer).unwrap() != 0 {}
+
+        let batch = &watcher.pending[0];
+        assert!(batch.dirty.contains(&existing.join("file")));
+        assert!(batch.dirty.contains(&dir.path().join("new")));
+        assert!(batch.rescan.contains(&dir.path().join("new")));
+    }
+
+    #[test]
+    fn events_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = "exclude cache".parse().unwrap();
+        let root = (dir.path().to_path_buf(), rules);
+        let mut watcher = Watcher::new(Settings::default(), vec![root]).unwrap();
+        fs::create_dir(dir.path().join("cache")).unwrap();
+        fs::write(dir.path().join("file"), b"").unwrap();
+
+        let mut buffer = [0; 4096];
+        while watcher.read_events(&mut buffer).unwrap() != 0 {}
+
+        let batch = &watcher.pending[0];
+        assert!(!batch.dirty.contains(&dir.path().join("cache")));
+        assert!(batch.rescan.is_empty());
+        assert!(batch.dirty.contains(&dir.path().join("file")));
+    }
+
+    #[test]
+    fn wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = (dir.path().to_path_buf(), PruneRules::default());
+        let mut watcher = Watcher::new(Settings::default(), vec![root]).unwrap();
+        let timeout = Some(Duration::from_millis(10));
+        assert!(!watcher.wait(timeout).unwrap());
+
+        fs::write(dir.path().join("file"), b"").unwrap();
+        assert!(watcher.wait(timeout).unwrap());
+        let mut buffer = [0; 4096];
+        while watcher.read_events(&mut buffer).unwrap() != 0 {}
+        assert!(!watcher.wait(timeout).unwrap());
+    }
+
+    #[test]
+    fn commit_unregistered() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = (dir.path().to_path_buf(), PruneRules::default());
+        let mut watcher = Watcher::new(Settings::default(), vec![root]).unwrap();
+        let mut db = Database::connect(":memory:").unwrap();
+        let mut buffer = [0; 4096];
+
+        fs::write(dir.path().join("file"), b"").unwrap();
+        while watcher.read_events(&mut buffer).unwrap() != 0 {}
+        let res = watcher.commit(&mut db, &[]);
+        assert!(matches!(res, Err(Error::NotRegistered(path)) if path == dir.path()));
+        assert!(db.volumes().unwrap().is_empty());
+
+        let volume = db
+            .volume_id(&SubvolumeSource::Find {
+                path: to_mixed(dir.path()),
+            })
+            .unwrap();
+        fs::write(dir.path().join("file"), b"changed").unwrap();
+        while watcher.read_events(&mut buffer).unwrap() != 0 {}
+        watcher.commit(&mut db, &[]).unwrap();
+        let files = db.load_files(volume, &key("")).unwrap();
+        assert_eq!(files[&key("file")].length, 7);
+    }
+
+    #[test]
+    fn delta_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+
+        let mut batch = Batch::default();
+        // Parent of the path is not a directory
+        batch.dirty.insert(file.join("child"));
+        let (delta, errors) = batch
+            .into_delta(dir.path(), &PruneRules::default(), |_| Ok(Vec::new()))
+            .unwrap();
+
+        assert!(delta.files.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, to_mixed(&file.join("child")));
+    }
+
+    #[test]
+    fn ignore_file_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        fs::create_dir(&cache).unwrap();
+        fs::write(dir.path().join(".gitignore"), b"cache\n").unwrap();
+
+        let rules = "ignore-file .gitignore".parse().unwrap();
+        let root = (dir.path().to_path_buf(), rules);
+        let mut watcher = Watcher::new(Settings::default(), vec![root]).unwrap();
+        assert!(!watcher.watches.values().any(|(_, path)| *path == cache));
+
+        let mut buffer = [0; 4096];
+        fs::write(dir.path().join(".gitignore"), b"").unwrap();
+        while watcher.read_events(&mut buffer).unwrap() != 0 {}
+        assert!(watcher.pending[0].rescan.contains(dir.path()));
+        assert!(watcher.watches.values().any(|(_, path)| *path == cache));
+
+        fs::write(cache.join("file"), b"").unwrap();
+        while watcher.read_events(&mut buffer).unwrap() != 0 {}
+        assert!(watcher.pending[0].dirty.contains(&cache.join("file")));
+        assert!(watcher.errors.is_empty());
+    }
+
+    #[test]
+    fn delta_missing_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("unmounted");
+
+        let mut batch = Batch::default();
+        batch.rescan.insert(root.clone());
+        let indexed = vec![key("file")];
+        let (delta, errors) = batch
+            .into_delta(&root, &PruneRules::default(), |_| Ok(indexed.clone()))
+            .unwrap();
+
+        assert!(delta.files.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, to_mixed(&root));
+    }
+}